@@ -24,6 +24,7 @@ pub mod clean;
 pub mod coverage;
 pub mod deps;
 pub mod doc;
+pub mod doctor;
 pub mod fmt;
 pub mod generate_test_driver;
 pub mod info;
@@ -35,6 +36,7 @@ pub mod run;
 pub mod shell_completion;
 pub mod test;
 pub mod tool;
+pub mod uninstall;
 pub mod update;
 pub mod upgrade;
 pub mod version;
@@ -47,10 +49,11 @@ pub use clean::*;
 pub use coverage::*;
 pub use deps::*;
 pub use doc::*;
+pub use doctor::*;
 pub use fmt::*;
 pub use generate_test_driver::*;
 pub use info::*;
-use moonbuild::upgrade::UpgradeSubcommand;
+use moonbuild::upgrade::{DoctorSubcommand, UninstallSubcommand, UpgradeSubcommand};
 use mooncake::pkg::{
     add::AddSubcommand, install::InstallSubcommand, remove::RemoveSubcommand, tree::TreeSubcommand,
 };
@@ -60,6 +63,7 @@ pub use run::*;
 pub use shell_completion::*;
 pub use test::*;
 pub use tool::*;
+pub use uninstall::*;
 pub use update::*;
 pub use upgrade::*;
 pub use version::*;
@@ -127,6 +131,10 @@ pub enum MoonBuildSubcommands {
 
     /// Upgrade toolchains
     Upgrade(UpgradeSubcommand),
+    /// Uninstall toolchains
+    Uninstall(UninstallSubcommand),
+    /// Check the installed toolchain for problems
+    Doctor(DoctorSubcommand),
     ShellCompletion(ShellCompSubCommand),
     Version(VersionSubcommand),
     #[clap(hide = true)]
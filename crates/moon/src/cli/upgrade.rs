@@ -16,14 +16,21 @@
 //
 // For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
 
-use anyhow::bail;
+use colored::Colorize;
 use moonbuild::upgrade::UpgradeSubcommand;
 
 use super::UniversalFlags;
 
 pub fn run_upgrade(cli: UniversalFlags, cmd: UpgradeSubcommand) -> anyhow::Result<i32> {
-    if cli.dry_run {
-        bail!("dry-run is not implemented for upgrade")
+    match moonbuild::upgrade::upgrade(cmd, cli.quiet, cli.verbose, cli.dry_run) {
+        Ok(code) => Ok(code),
+        // scripts tell these failures apart by the exit code
+        Err(e) => match e.exit_code() {
+            Some(code) => {
+                eprintln!("{}: {:?}", "error".red().bold(), anyhow::Error::from(e));
+                Ok(code)
+            }
+            None => Err(e.into()),
+        },
     }
-    moonbuild::upgrade::upgrade(cmd)
 }
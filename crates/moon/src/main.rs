@@ -88,6 +88,8 @@ fn main1() -> anyhow::Result<i32> {
         Tree(t) => cli::tree_cli(flags, t),
         Update(u) => cli::update_cli(flags, u),
         Upgrade(u) => cli::run_upgrade(flags, u),
+        Uninstall(u) => cli::run_uninstall(flags, u),
+        Doctor(d) => cli::run_doctor(flags, d),
         ShellCompletion(gs) => cli::gen_shellcomp(&flags, gs),
         Version(v) => cli::run_version(v),
         Tool(v) => cli::run_tool(v),
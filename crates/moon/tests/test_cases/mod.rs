@@ -396,6 +396,60 @@ fn test_moon_new() {
     );
 }
 
+#[cfg(unix)]
+#[test]
+fn test_moon_upgrade_quiet() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // a moon home with the moon saved by an earlier upgrade
+    let dir = TestDir::new_empty();
+    let bin = dir.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::write(bin.join("moon"), "#!/bin/sh\necho moon 0.1.20240903\n").unwrap();
+    let saved = bin.join("moon.bak");
+    std::fs::write(&saved, "#!/bin/sh\necho moon 0.1.20240828\n").unwrap();
+    std::fs::set_permissions(&saved, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    snapbox::cmd::Command::new(moon_bin())
+        .current_dir(&dir)
+        .args(["upgrade", "--quiet", "--yes", "--revert", "--prefix", "."])
+        .assert()
+        .success()
+        .stdout_eq("");
+    assert!(read(bin.join("moon")).contains("0.1.20240828"));
+}
+
+#[test]
+fn test_moon_uninstall_yes() {
+    let dir = TestDir::new_empty();
+    let bin = dir.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::write(bin.join("moon"), "").unwrap();
+    let home: &std::path::Path = dir.as_ref();
+
+    // piped input can't answer the prompt
+    let err = snapbox::cmd::Command::new(moon_bin())
+        .env("MOON_HOME", home)
+        .args(["uninstall"])
+        .stdin("y\n")
+        .assert()
+        .failure()
+        .get_output()
+        .stderr
+        .to_owned();
+    let err = String::from_utf8_lossy(&err);
+    assert!(err.contains("pass `--yes` to uninstall anyway"), "{}", err);
+    assert!(bin.join("moon").exists());
+
+    snapbox::cmd::Command::new(moon_bin())
+        .env("MOON_HOME", home)
+        .args(["uninstall", "--yes"])
+        .stdin("")
+        .assert()
+        .success();
+    assert!(!bin.join("moon").exists());
+}
+
 #[test]
 fn test_moon_help() {
     let dir = TestDir::new_empty();
@@ -428,6 +482,8 @@ fn test_moon_help() {
               coverage               Code coverage utilities
               generate-build-matrix  Generate build matrix for benchmarking (legacy feature)
               upgrade                Upgrade toolchains
+              uninstall              Uninstall toolchains
+              doctor                 Check the installed toolchain for problems
               shell-completion       Generate shell completion for bash/elvish/fish/pwsh/zsh to stdout
               version                Print version information and exit
               help                   Print this message or the help of the given subcommand(s)
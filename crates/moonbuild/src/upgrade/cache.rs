@@ -0,0 +1,93 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Cache of the latest `version.json`, so repeated runs don't hit the network
+
+use anyhow::{Context, Result};
+use moonutil::common::VersionItems;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File in the moon home holding the cached `version.json`
+pub const VERSION_CACHE: &str = "version-cache.json";
+
+/// How long a cached `version.json` is used before fetching it again
+pub const VERSION_CACHE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct CachedVersions {
+    /// `version.json` of different channels or mirrors are not mixed up
+    url: String,
+    /// Seconds since the unix epoch
+    fetched_at: u64,
+    content: String,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// The cached `version.json` of `url`, `None` if there is none or it was
+/// fetched more than `ttl` before `now`
+pub fn read_cached_versions(
+    home: &Path,
+    url: &str,
+    ttl: Duration,
+    now: SystemTime,
+) -> Option<VersionItems> {
+    let content = std::fs::read_to_string(home.join(VERSION_CACHE)).ok()?;
+    let cached = serde_json_lenient::from_str::<CachedVersions>(&content).ok()?;
+    // a timestamp in the future means the clock was changed, don't trust it
+    let age = unix_secs(now).checked_sub(cached.fetched_at)?;
+    if cached.url != url || age >= ttl.as_secs() {
+        return None;
+    }
+    serde_json_lenient::from_str(&cached.content).ok()
+}
+
+/// Remember `content`, the `version.json` fetched from `url` at `now`
+pub fn write_cached_versions(home: &Path, url: &str, content: &str, now: SystemTime) -> Result<()> {
+    let cached = CachedVersions {
+        url: url.to_string(),
+        fetched_at: unix_secs(now),
+        content: content.to_string(),
+    };
+    let path = home.join(VERSION_CACHE);
+    std::fs::write(&path, serde_json_lenient::to_string(&cached)?)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[test]
+fn test_version_cache() {
+    let home = tempfile::tempdir().unwrap();
+    let url = "https://cli.moonbitlang.com/version.json";
+    let content = r#"{"items":[{"name":"moon","version":"v0.1.20240828+901ac0751"}]}"#;
+    let now = SystemTime::now();
+    assert!(read_cached_versions(home.path(), url, VERSION_CACHE_TTL, now).is_none());
+
+    write_cached_versions(home.path(), url, content, now).unwrap();
+    let cached = read_cached_versions(home.path(), url, VERSION_CACHE_TTL, now).unwrap();
+    assert_eq!("moon", cached.items[0].name);
+
+    let later = now + VERSION_CACHE_TTL;
+    assert!(read_cached_versions(home.path(), url, VERSION_CACHE_TTL, later).is_none());
+    let other_url = "https://cli.moonbitlang.cn/version.json";
+    assert!(read_cached_versions(home.path(), other_url, VERSION_CACHE_TTL, now).is_none());
+    let earlier = now - Duration::from_secs(60);
+    assert!(read_cached_versions(home.path(), url, VERSION_CACHE_TTL, earlier).is_none());
+}
@@ -0,0 +1,233 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Integrity checks of the downloaded toolchain files
+
+use super::download::{Downloader, NotFound};
+use anyhow::{bail, Context, Result};
+use futures::stream::TryStreamExt;
+use minisign_verify::{PublicKey, Signature};
+use mooncake::registry::online::calc_sha2;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Checksum manifest at the root of the mirror
+pub const SHA256SUMS: &str = "SHA256SUMS";
+
+/// Detached minisign signature of [`SHA256SUMS`]
+pub const SHA256SUMS_SIG: &str = "SHA256SUMS.minisig";
+
+/// Minisign public key the toolchain releases are signed with, bundled at
+/// build time from `MOON_TOOLCHAIN_PUBLIC_KEY`. Without it, only the
+/// checksums are verified: the official mirrors don't publish
+/// `SHA256SUMS.minisig` yet.
+pub fn trusted_public_key() -> Option<&'static str> {
+    option_env!("MOON_TOOLCHAIN_PUBLIC_KEY")
+}
+
+/// Expected SHA-256 digests, keyed by file path relative to the mirror root
+#[derive(Debug, Default)]
+pub struct Checksums {
+    sums: HashMap<String, String>,
+}
+
+impl Checksums {
+    /// Parse `sha256sum` output, one `<digest>  <path>` per line
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut sums = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (digest, path) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("malformed line {} in {}", i + 1, SHA256SUMS))?;
+            if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("malformed digest on line {} in {}", i + 1, SHA256SUMS);
+            }
+            // a leading `*` marks binary mode
+            let path = path.trim_start().trim_start_matches('*');
+            sums.insert(path.to_string(), digest.to_ascii_lowercase());
+        }
+        Ok(Checksums { sums })
+    }
+
+    /// Whether there is a digest for `path`
+    pub fn contains(&self, path: &str) -> bool {
+        self.sums.contains_key(path)
+    }
+
+    /// Check that `file` matches the expected digest of `path`
+    pub fn verify(&self, path: &str, file: &Path) -> Result<()> {
+        let expected = self
+            .sums
+            .get(path)
+            .with_context(|| format!("no checksum for {} in {}", path, SHA256SUMS))?;
+        let actual =
+            calc_sha2(file).with_context(|| format!("failed to hash {}", file.display()))?;
+        if &actual != expected {
+            bail!(
+                "checksum mismatch for {}: expected {}, got {}",
+                path,
+                expected,
+                actual
+            );
+        }
+        Ok(())
+    }
+}
+
+fn verify_signature(public_key: &str, content: &[u8], signature: &str) -> Result<()> {
+    let public_key = PublicKey::from_base64(public_key).context("invalid toolchain public key")?;
+    let signature =
+        Signature::decode(signature).with_context(|| format!("malformed {}", SHA256SUMS_SIG))?;
+    public_key
+        .verify(content, &signature, false)
+        .with_context(|| format!("{} doesn't match {}", SHA256SUMS, SHA256SUMS_SIG))
+}
+
+/// `None` if the mirror doesn't have the file
+pub async fn fetch_text(downloader: &dyn Downloader, url: &str) -> Result<Option<String>> {
+    let fetched = match downloader.fetch(url, None).await {
+        Ok(fetched) => fetched,
+        Err(e) if e.is::<NotFound>() => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let content = fetched
+        .body
+        .try_fold(vec![], |mut content, chunk| async move {
+            content.extend_from_slice(&chunk);
+            Ok(content)
+        })
+        .await?;
+    let content = String::from_utf8(content).with_context(|| format!("failed to read {}", url))?;
+    Ok(Some(content))
+}
+
+/// Parse the manifest found in `origin`, checking it with `signature` when
+/// there is a `public_key`. With a `public_key`, both must be present.
+pub fn load_checksums(
+    origin: &str,
+    content: Option<String>,
+    signature: Option<String>,
+    public_key: Option<&str>,
+) -> Result<Option<Checksums>> {
+    let content = match content {
+        Some(content) => content,
+        None if public_key.is_some() => bail!(
+            "{} is not provided by {}, pass `--no-verify` to install without verification",
+            SHA256SUMS,
+            origin
+        ),
+        None => return Ok(None),
+    };
+    if let Some(public_key) = public_key {
+        let signature = signature.with_context(|| {
+            format!(
+                "{} is not provided by {}, pass `--no-verify` to install without verification",
+                SHA256SUMS_SIG, origin
+            )
+        })?;
+        verify_signature(public_key, content.as_bytes(), &signature)?;
+    }
+    Ok(Some(Checksums::parse(&content)?))
+}
+
+/// Fetch the checksum manifest and, when it is to be checked with a
+/// `public_key`, its signature. `None` for files the mirror doesn't provide.
+pub async fn fetch_manifest(
+    downloader: &dyn Downloader,
+    root: &str,
+    public_key: Option<&str>,
+) -> Result<(Option<String>, Option<String>)> {
+    let content = fetch_text(downloader, &format!("{}/{}", root, SHA256SUMS)).await?;
+    let signature = if public_key.is_some() && content.is_some() {
+        fetch_text(downloader, &format!("{}/{}", root, SHA256SUMS_SIG)).await?
+    } else {
+        None
+    };
+    Ok((content, signature))
+}
+
+/// [`load_checksums`] for a toolchain laid out like the mirror in `dir`
+pub fn read_checksums(dir: &Path, public_key: Option<&str>) -> Result<Option<Checksums>> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    };
+    let content = read(SHA256SUMS)?;
+    let signature = if public_key.is_some() && content.is_some() {
+        read(SHA256SUMS_SIG)?
+    } else {
+        None
+    };
+    load_checksums(&dir.display().to_string(), content, signature, public_key)
+}
+
+#[test]
+fn test_trusted_public_key() {
+    // a build must not ship a key that can't verify anything
+    if let Some(key) = trusted_public_key() {
+        PublicKey::from_base64(key).unwrap();
+    }
+}
+
+#[test]
+fn test_verify_checksum() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("moon");
+    std::fs::write(&file, b"hello").unwrap();
+
+    let checksums = Checksums::parse(
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  macos_m1/bin/moon\n\
+         0000000000000000000000000000000000000000000000000000000000000000 *core.zip\n",
+    )
+    .unwrap();
+    assert!(checksums.verify("macos_m1/bin/moon", &file).is_ok());
+    assert!(checksums.verify("core.zip", &file).is_err());
+    assert!(checksums.verify("bin/moonc", &file).is_err());
+    assert!(Checksums::parse("not-a-digest  core.zip").is_err());
+}
+
+#[test]
+fn test_fetch_manifest() {
+    use super::download::MemoryDownloader;
+
+    let mirror = MemoryDownloader(
+        [("https://mirror/SHA256SUMS".to_string(), b"sums".to_vec())]
+            .into_iter()
+            .collect(),
+    );
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let fetch = |root, public_key| rt.block_on(fetch_manifest(&mirror, root, public_key));
+    assert_eq!(
+        (Some("sums".to_string()), None),
+        fetch("https://mirror", None).unwrap()
+    );
+    // the signature is only fetched to be checked, and may be missing
+    assert_eq!(
+        (Some("sums".to_string()), None),
+        fetch("https://mirror", Some("key")).unwrap()
+    );
+    assert_eq!((None, None), fetch("https://other", Some("key")).unwrap());
+}
@@ -0,0 +1,165 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Keeping the files users added to `lib/core` across upgrades
+
+use super::output::Output;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Files extracted from the core archive, relative to `lib/core`, one per line.
+/// Anything else in `lib/core` was added by the user.
+pub const CORE_MANIFEST: &str = "lib/core.files";
+
+/// Bundles are built for every new core, they are never carried over
+const BUNDLE_DIR: &str = "target";
+
+/// Files in `core`, as `/` separated relative paths
+fn list_files(core: &Path) -> Result<BTreeSet<String>> {
+    let mut files = BTreeSet::new();
+    let walker = walkdir::WalkDir::new(core)
+        .into_iter()
+        .filter_entry(|entry| !(entry.depth() == 1 && entry.file_name() == BUNDLE_DIR));
+    for entry in walker {
+        let entry = entry.with_context(|| format!("failed to read {}", core.display()))?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(core)?;
+        files.insert(
+            rel.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+        );
+    }
+    Ok(files)
+}
+
+/// Record the files of the core extracted to `staging`, a directory laid out
+/// like the moon home
+pub fn write_manifest(staging: &Path) -> Result<()> {
+    let files = list_files(&staging.join("lib").join("core"))?;
+    let path = staging.join(CORE_MANIFEST);
+    let mut content = files.into_iter().collect::<Vec<_>>().join("\n");
+    content.push('\n');
+    std::fs::write(&path, content).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Copy the files the user added to the installed core into the core staged
+/// in `staging`. Without the manifest of the installed core, they can't be
+/// told apart from files the new core dropped, so they are only reported.
+/// Returns the files that were kept.
+pub fn carry_over_user_files(
+    home: &Path,
+    staging: &Path,
+    keep_backups: usize,
+    out: Output,
+) -> Result<Vec<String>> {
+    let installed = home.join("lib").join("core");
+    if !installed.is_dir() {
+        return Ok(vec![]);
+    }
+    let staged_core = staging.join("lib").join("core");
+    let staged = list_files(&staged_core)?;
+    let extra = list_files(&installed)?
+        .into_iter()
+        .filter(|file| !staged.contains(file))
+        .collect::<Vec<_>>();
+    if extra.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let distributed = match std::fs::read_to_string(home.join(CORE_MANIFEST)) {
+        Ok(content) => content.lines().map(str::to_string).collect::<BTreeSet<_>>(),
+        Err(_) => {
+            let mut msg = format!(
+                "Warning: these files in {} are not part of the new core and will be removed",
+                installed.display()
+            );
+            if keep_backups > 0 {
+                msg.push_str(", a copy is kept in the backup");
+            }
+            for file in &extra {
+                msg.push_str(&format!("\n  {}", file));
+            }
+            out.warn(&msg);
+            return Ok(vec![]);
+        }
+    };
+    let user_files = extra
+        .iter()
+        .filter(|file| !distributed.contains(*file))
+        .collect::<Vec<_>>();
+    for file in &user_files {
+        let dst = staged_core.join(file);
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        std::fs::copy(installed.join(file), &dst)
+            .with_context(|| format!("failed to keep {} in the new core", file))?;
+    }
+    Ok(user_files.into_iter().cloned().collect())
+}
+
+#[test]
+fn test_carry_over_user_files() {
+    let home = tempfile::tempdir().unwrap();
+    let staging = tempfile::tempdir().unwrap();
+    let write = |root: &Path, rel: &str| {
+        let path = root.join(rel);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, rel).unwrap();
+    };
+    for rel in [
+        "lib/core/builtin/a.mbt",
+        "lib/core/builtin/removed.mbt",
+        "lib/core/mine/patch.mbt",
+        "lib/core/target/wasm-gc/release/bundle/core.core",
+    ] {
+        write(home.path(), rel);
+    }
+    std::fs::write(
+        home.path().join(CORE_MANIFEST),
+        "builtin/a.mbt\nbuiltin/removed.mbt\n",
+    )
+    .unwrap();
+    write(staging.path(), "lib/core/builtin/a.mbt");
+
+    let out = Output {
+        quiet: true,
+        ..Default::default()
+    };
+    // the manifest only lists the distributed files
+    write_manifest(staging.path()).unwrap();
+    assert_eq!(
+        vec!["mine/patch.mbt"],
+        carry_over_user_files(home.path(), staging.path(), 0, out).unwrap()
+    );
+
+    let core = staging.path().join("lib/core");
+    assert!(core.join("mine/patch.mbt").is_file());
+    assert!(!core.join("builtin/removed.mbt").exists());
+    assert!(!core.join("target").exists());
+    assert_eq!(
+        "builtin/a.mbt\n",
+        std::fs::read_to_string(staging.path().join(CORE_MANIFEST)).unwrap()
+    );
+}
@@ -0,0 +1,261 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! `moon doctor`, a checklist of what may be wrong with the installed
+//! toolchain, to attach to bug reports
+
+use super::files::REQUIRED_BINARIES;
+use super::{component_versions_in, extract_date, shell_path};
+use colored::Colorize;
+use moonutil::moon_dir;
+use std::ffi::OsStr;
+use std::path::Path;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+#[derive(Debug, clap::Parser, Clone)]
+pub struct DoctorSubcommand {}
+
+/// The components of a release are built on close, but not always the same,
+/// days
+const MAX_DATE_SPREAD_DAYS: i64 = 7;
+
+/// Written by `moon bundle --all` for the default backend
+const BUNDLED_CORE: &str = "lib/core/target/wasm-gc/release/bundle/core.core";
+
+/// A line of the checklist
+struct Check {
+    what: String,
+    /// Why the check failed and how to fix it
+    failure: Option<(String, String)>,
+}
+
+impl Check {
+    fn pass(what: impl Into<String>) -> Self {
+        Check {
+            what: what.into(),
+            failure: None,
+        }
+    }
+
+    fn fail(what: impl Into<String>, reason: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check {
+            what: what.into(),
+            failure: Some((reason.into(), hint.into())),
+        }
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+/// Check the toolchain installed in `home`, with `path` as the value of PATH
+fn run_checks(home: &Path, path: &OsStr) -> Vec<Check> {
+    let bin = home.join("bin");
+    let reinstall = "run `moon upgrade --force` to reinstall the toolchain";
+    let mut checks = vec![];
+
+    let versions = component_versions_in(&bin);
+    let mut dates = vec![];
+    for (name, version) in versions.into_iter().take(REQUIRED_BINARIES.len()) {
+        let exe = bin.join(format!("{}{}", name, std::env::consts::EXE_SUFFIX));
+        let what = format!("{} is installed", name);
+        if !exe.exists() {
+            checks.push(Check::fail(
+                what,
+                format!("{} doesn't exist", exe.display()),
+                reinstall,
+            ));
+            continue;
+        }
+        if !is_executable(&exe) {
+            checks.push(Check::fail(
+                what,
+                format!("{} is not executable", exe.display()),
+                format!("run `chmod +x {}`, or {}", exe.display(), reinstall),
+            ));
+            continue;
+        }
+        checks.push(Check::pass(what));
+
+        let what = format!("{} reports its version", name);
+        match version
+            .as_deref()
+            .map(|version| (version, extract_date(version)))
+        {
+            Some((version, Ok(date))) => {
+                checks.push(Check::pass(format!("{}: {}", what, version)));
+                dates.push((name, date));
+            }
+            Some((version, Err(_))) => checks.push(Check::fail(
+                what,
+                format!("unexpected version `{}`", version),
+                reinstall,
+            )),
+            None => checks.push(Check::fail(
+                what,
+                format!("{} doesn't run", exe.display()),
+                reinstall,
+            )),
+        }
+    }
+
+    let parsed = dates
+        .iter()
+        .filter_map(|(_, date)| chrono::NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+        .collect::<Vec<_>>();
+    if let (Some(oldest), Some(newest)) = (parsed.iter().min(), parsed.iter().max()) {
+        let what = "the components are from the same release";
+        if (*newest - *oldest).num_days() > MAX_DATE_SPREAD_DAYS {
+            let dates = dates
+                .iter()
+                .map(|(name, date)| format!("{} {}", name, date))
+                .collect::<Vec<_>>()
+                .join(", ");
+            checks.push(Check::fail(what, dates, reinstall));
+        } else {
+            checks.push(Check::pass(what));
+        }
+    }
+
+    let core = home.join("lib").join("core");
+    if core.is_dir() {
+        checks.push(Check::pass("the core is installed"));
+        if home.join(BUNDLED_CORE).is_file() {
+            checks.push(Check::pass("the core is bundled"));
+        } else {
+            checks.push(Check::fail(
+                "the core is bundled",
+                format!("{} doesn't exist", home.join(BUNDLED_CORE).display()),
+                format!("run `moon bundle --all --source-dir {}`", core.display()),
+            ));
+        }
+    } else {
+        checks.push(Check::fail(
+            "the core is installed",
+            format!("{} doesn't exist", core.display()),
+            reinstall,
+        ));
+    }
+
+    let what = "moon is on PATH";
+    if shell_path::in_path(path, &bin) {
+        checks.push(Check::pass(what));
+    } else {
+        checks.push(Check::fail(
+            what,
+            format!("{} is not on PATH", bin.display()),
+            format!(
+                "run `moon upgrade --add-to-path`, or {}",
+                shell_path::path_instructions(&bin)
+            ),
+        ));
+    }
+    checks
+}
+
+/// Print the checklist, only the failed checks with `quiet`. Exits with 1 if
+/// any check fails.
+pub fn doctor(_cmd: DoctorSubcommand, quiet: bool) -> anyhow::Result<i32> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let checks = run_checks(&moon_dir::home(), &path);
+    let mut failed = 0;
+    for check in &checks {
+        match &check.failure {
+            None => {
+                if !quiet {
+                    println!("{} {}", "[ok]".green(), check.what);
+                }
+            }
+            Some((reason, hint)) => {
+                failed += 1;
+                println!("{} {}: {}", "[failed]".red().bold(), check.what, reason);
+                for line in hint.lines() {
+                    println!("         {}", line.dimmed());
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        println!();
+        println!("{} of {} checks failed", failed, checks.len());
+        return Ok(1);
+    }
+    if !quiet {
+        println!();
+        println!("No problems found");
+    }
+    Ok(0)
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_checks() {
+    let home = tempfile::tempdir().unwrap();
+    let bin = home.path().join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    for (name, version) in [
+        ("moon", "moon 0.1.20240828 (901ac075 2024-08-28)"),
+        ("moonc", "v0.1.20240827+848d2bb76"),
+        ("moonrun", "moonrun 0.1.20240901"),
+    ] {
+        let path = bin.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\necho '{}'\n", version)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    std::fs::create_dir_all(home.path().join("lib/core")).unwrap();
+
+    let failed = |path: &OsStr| {
+        run_checks(home.path(), path)
+            .into_iter()
+            .filter(|check| check.failure.is_some())
+            .map(|check| check.what)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        vec!["the core is bundled", "moon is on PATH"],
+        failed(OsStr::new("/usr/bin"))
+    );
+
+    std::fs::set_permissions(bin.join("moonc"), std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::fs::write(
+        bin.join("moonrun"),
+        "#!/bin/sh\necho 'moonrun 0.1.20250101'\n",
+    )
+    .unwrap();
+    std::fs::create_dir_all(home.path().join(BUNDLED_CORE).parent().unwrap()).unwrap();
+    std::fs::write(home.path().join(BUNDLED_CORE), "").unwrap();
+    let path = std::env::join_paths([Path::new("/usr/bin"), bin.as_path()]).unwrap();
+    assert_eq!(
+        vec![
+            "moonc is installed",
+            "the components are from the same release"
+        ],
+        failed(&path)
+    );
+}
@@ -0,0 +1,966 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Downloading of the toolchain files, resuming where an earlier attempt
+//! stopped when the server supports range requests
+
+use super::files::{self, ToolchainFile};
+use super::mirrors::alternate_root;
+use super::output::{Output, UpgradeEvent};
+use super::progress::{self, ProgressEvent};
+use super::throttle::RateLimiter;
+use super::HttpOptions;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use rand::Rng;
+use reqwest::header::{
+    HeaderMap, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    ETAG, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER,
+};
+use reqwest::StatusCode;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// The file doesn't exist on the mirror, retrying won't help
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is not found, the mirror may not provide this build yet")]
+pub struct NotFound(pub String);
+
+/// The offset to resume at is not within the file, with a 416. `length` is
+/// the size of the file, if the server tells it in `Content-Range`.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to download {url}: 416 Range Not Satisfiable")]
+pub struct RangeNotSatisfiable {
+    pub url: String,
+    pub length: Option<u64>,
+}
+
+/// The server is busy and asked to retry after `delay`, with a 429 or a 503
+/// and `Retry-After`
+#[derive(Debug, thiserror::Error)]
+#[error("failed to download {url}: {status}, retry after {}s", .delay.as_secs())]
+pub struct RetryAfter {
+    pub url: String,
+    pub status: StatusCode,
+    pub delay: Duration,
+}
+
+/// Server-directed waits of a download add up to at most this, so that a
+/// misbehaving server can't hang the upgrade
+const MAX_RETRY_AFTER_WAIT: Duration = Duration::from_secs(300);
+
+/// The delay of a `Retry-After` header, either seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let delay = date.signed_duration_since(chrono::Utc::now());
+    Some(delay.to_std().unwrap_or(Duration::ZERO))
+}
+
+/// A response whose body is being received
+pub struct Fetched {
+    /// Whether the body continues a partial download instead of starting over
+    pub resumed: bool,
+    /// Length of the body, `None` if the server doesn't tell
+    pub content_length: Option<u64>,
+    /// ETag or Last-Modified, only if the server accepts range requests for
+    /// the file
+    pub validator: Option<String>,
+    pub body: BoxStream<'static, Result<Bytes>>,
+}
+
+/// Sends the requests of the upgrade, so that tests can serve the toolchain
+/// without the network
+pub trait Downloader: Send + Sync {
+    /// Request `url`, or with `resume`, the part after the given offset if the
+    /// file still matches the given validator. Fails with [`NotFound`] if the
+    /// file doesn't exist, and with [`RangeNotSatisfiable`] if the offset is
+    /// not before its end.
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        resume: Option<(u64, &'a str)>,
+    ) -> BoxFuture<'a, Result<Fetched>>;
+
+    /// Size of `url` without downloading it, `None` if the server doesn't
+    /// tell
+    fn size<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Option<u64>> {
+        Box::pin(async { None })
+    }
+}
+
+impl Downloader for reqwest::Client {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        resume: Option<(u64, &'a str)>,
+    ) -> BoxFuture<'a, Result<Fetched>> {
+        Box::pin(async move {
+            if let Some(path) = local_path(url) {
+                return fetch_local(&path, url, resume).await;
+            }
+            let mut request = self.get(url);
+            if let Some((offset, validator)) = resume {
+                // `If-Range` makes the server send the whole file again if
+                // it has changed in the meantime
+                // the offset is in decoded bytes, so the range must not be
+                // of a compressed response
+                request = request
+                    .header(RANGE, format!("bytes={}-", offset))
+                    .header(IF_RANGE, validator)
+                    .header(ACCEPT_ENCODING, "identity");
+            }
+            let response = request
+                .send()
+                .await
+                .with_context(|| format!("failed to download {}", url))?;
+            let status = response.status();
+            if status == StatusCode::NOT_FOUND {
+                return Err(NotFound(url.to_string()).into());
+            }
+            if matches!(
+                status,
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+            ) {
+                let delay = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_retry_after);
+                if let Some(delay) = delay {
+                    return Err(RetryAfter {
+                        url: url.to_string(),
+                        status,
+                        delay,
+                    }
+                    .into());
+                }
+            }
+            if status == StatusCode::RANGE_NOT_SATISFIABLE {
+                // `bytes */<length>`
+                let length = response
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.strip_prefix("bytes */"))
+                    .and_then(|v| v.trim().parse().ok());
+                return Err(RangeNotSatisfiable {
+                    url: url.to_string(),
+                    length,
+                }
+                .into());
+            }
+            if !status.is_success() {
+                bail!("failed to download {}: {}", url, status);
+            }
+
+            let accepts_ranges = response
+                .headers()
+                .get(ACCEPT_RANGES)
+                .is_some_and(|v| v.as_bytes() == b"bytes");
+            let validator = response
+                .headers()
+                .get(ETAG)
+                .or_else(|| response.headers().get(LAST_MODIFIED))
+                .and_then(|v| v.to_str().ok())
+                .filter(|_| accepts_ranges)
+                .map(str::to_string);
+            let url = url.to_string();
+            Ok(Fetched {
+                resumed: status == StatusCode::PARTIAL_CONTENT,
+                // chunked responses, or proxies stripping the header, don't
+                // tell the size. Neither do compressed ones, which reqwest
+                // decodes, as only the compressed size is known.
+                content_length: response.content_length(),
+                validator,
+                body: response
+                    .bytes_stream()
+                    .map(move |chunk| {
+                        chunk.with_context(|| format!("error while downloading {}", url))
+                    })
+                    .boxed(),
+            })
+        })
+    }
+
+    fn size<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Option<u64>> {
+        Box::pin(async move {
+            if let Some(path) = local_path(url) {
+                return tokio::fs::metadata(path).await.ok().map(|m| m.len());
+            }
+            let response = self.head(url).send().await.ok()?;
+            if !response.status().is_success() {
+                return None;
+            }
+            decoded_length(response.headers())
+        })
+    }
+}
+
+/// The size of the file from the `Content-Length` of a HEAD response, which
+/// has no body to measure. `None` if the file would be sent compressed, the
+/// header is then the compressed size.
+pub fn decoded_length(headers: &HeaderMap) -> Option<u64> {
+    if headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes() != b"identity")
+    {
+        return None;
+    }
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// The file a `file://` url points to, `None` for other urls
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    let url = reqwest::Url::parse(url).ok()?;
+    if url.scheme() != "file" {
+        return None;
+    }
+    url.to_file_path().ok()
+}
+
+/// Body of `url`, read from disk for `file://` urls
+pub fn get_text(client: &reqwest::blocking::Client, url: &str) -> Result<String> {
+    if let Some(path) = local_path(url) {
+        return std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()));
+    }
+    Ok(client.get(url).send()?.error_for_status()?.text()?)
+}
+
+/// How much of a local file is read at once
+const LOCAL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Read the file at `path` like a response to `url`, so that a directory
+/// can be used as a mirror. The modification time is the validator, so
+/// resuming starts over if the file was replaced.
+async fn fetch_local(path: &Path, url: &str, resume: Option<(u64, &str)>) -> Result<Fetched> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(NotFound(url.to_string()).into())
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to open {}", path.display())),
+    };
+    let metadata = file
+        .metadata()
+        .await
+        .with_context(|| format!("failed to read metadata of {}", path.display()))?;
+    let validator = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| format!("{}.{:09}", since.as_secs(), since.subsec_nanos()));
+    let offset = match (resume, &validator) {
+        (Some((offset, expected)), Some(validator))
+            if expected == validator && offset <= metadata.len() =>
+        {
+            offset
+        }
+        _ => 0,
+    };
+    if offset > 0 {
+        file.seek(std::io::SeekFrom::Start(offset))
+            .await
+            .with_context(|| format!("failed to seek in {}", path.display()))?;
+    }
+
+    let url = url.to_string();
+    let body = futures::stream::try_unfold(file, move |mut file| {
+        let url = url.clone();
+        async move {
+            let mut chunk = vec![0; LOCAL_CHUNK_SIZE];
+            let n = file
+                .read(&mut chunk)
+                .await
+                .with_context(|| format!("error while reading {}", url))?;
+            if n == 0 {
+                return Ok(None);
+            }
+            chunk.truncate(n);
+            Ok(Some((Bytes::from(chunk), file)))
+        }
+    });
+    Ok(Fetched {
+        resumed: offset > 0,
+        content_length: Some(metadata.len() - offset),
+        validator,
+        body: body.boxed(),
+    })
+}
+
+/// Serves files from memory by url, resuming at any offset
+#[cfg(test)]
+pub struct MemoryDownloader(pub std::collections::HashMap<String, Vec<u8>>);
+
+#[cfg(test)]
+impl Downloader for MemoryDownloader {
+    fn fetch<'a>(
+        &'a self,
+        url: &'a str,
+        resume: Option<(u64, &'a str)>,
+    ) -> BoxFuture<'a, Result<Fetched>> {
+        Box::pin(async move {
+            let content = match self.0.get(url) {
+                Some(content) => content,
+                None => return Err(NotFound(url.to_string()).into()),
+            };
+            let offset = resume.map_or(0, |(offset, _)| offset as usize);
+            if resume.is_some() && offset >= content.len() {
+                return Err(RangeNotSatisfiable {
+                    url: url.to_string(),
+                    length: Some(content.len() as u64),
+                }
+                .into());
+            }
+            let body = Bytes::copy_from_slice(&content[offset..]);
+            Ok(Fetched {
+                resumed: resume.is_some(),
+                content_length: Some(body.len() as u64),
+                validator: Some("\"memory\"".to_string()),
+                body: futures::stream::once(async move { Ok(body) }).boxed(),
+            })
+        })
+    }
+
+    fn size<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Option<u64>> {
+        Box::pin(async move { self.0.get(url).map(|content| content.len() as u64) })
+    }
+}
+
+/// Partial downloads are kept in `dir` across runs, named after their url
+fn partial_path(dir: &Path, url: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create directory {}", dir.display()))?;
+    let name = url
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    Ok(dir.join(format!("{}.part", name)))
+}
+
+/// Move the complete download `part` to `dest`
+async fn finish_part(part: &Path, validator_path: &Path, dest: &Path) -> Result<()> {
+    tokio::fs::rename(part, dest)
+        .await
+        .with_context(|| format!("failed to move {} to {}", part.display(), dest.display()))?;
+    let _ = tokio::fs::remove_file(validator_path).await;
+    Ok(())
+}
+
+/// Download `url` to `dest`, keeping what was received in `partial_dir` until
+/// it is complete. `on_progress` is called with the downloaded and
+/// the total size in bytes, `None` when the server doesn't send a
+/// `Content-Length`, and reading the body is throttled by `limiter`.
+///
+/// A partial download is only resumed when the server advertised
+/// `Accept-Ranges: bytes` for it, and only if the file hasn't changed since.
+/// If there is nothing left to resume, the partial download is kept when it
+/// has the size of the file, and started over otherwise.
+pub async fn download_file(
+    downloader: &dyn Downloader,
+    url: &str,
+    dest: &Path,
+    partial_dir: &Path,
+    limiter: Option<&RateLimiter>,
+    mut on_progress: impl FnMut(u64, Option<u64>),
+) -> Result<()> {
+    let part = partial_path(partial_dir, url)?;
+    // ETag or Last-Modified of the partial download
+    let validator_path = PathBuf::from(format!("{}.validator", part.display()));
+
+    let offset = tokio::fs::metadata(&part).await.map_or(0, |m| m.len());
+    let validator = tokio::fs::read_to_string(&validator_path).await.ok();
+
+    let resume = match &validator {
+        Some(validator) if offset > 0 => Some((offset, validator.as_str())),
+        _ => None,
+    };
+    let fetched = match downloader.fetch(url, resume).await {
+        Ok(fetched) => fetched,
+        Err(e) if resume.is_some() && e.is::<RangeNotSatisfiable>() => {
+            let length = e
+                .downcast_ref::<RangeNotSatisfiable>()
+                .and_then(|e| e.length);
+            if length == Some(offset) {
+                // an earlier attempt stopped right after receiving all of it
+                on_progress(offset, Some(offset));
+                return finish_part(&part, &validator_path, dest).await;
+            }
+            log::debug!("{:#}, starting over", e);
+            let _ = tokio::fs::remove_file(&part).await;
+            let _ = tokio::fs::remove_file(&validator_path).await;
+            downloader.fetch(url, None).await?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let resumed = fetched.resumed;
+    let mut downloaded = if resumed { offset } else { 0 };
+    let total_size = fetched.content_length.map(|len| downloaded + len);
+
+    if !resumed {
+        match &fetched.validator {
+            Some(v) => tokio::fs::write(&validator_path, v)
+                .await
+                .with_context(|| format!("failed to write {}", validator_path.display()))?,
+            None => {
+                let _ = tokio::fs::remove_file(&validator_path).await;
+            }
+        }
+    }
+
+    let mut file = if resumed {
+        tokio::fs::OpenOptions::new().append(true).open(&part).await
+    } else {
+        tokio::fs::File::create(&part).await
+    }
+    .with_context(|| format!("failed to open file {}", part.display()))?;
+
+    on_progress(downloaded, total_size);
+    let mut body = fetched.body;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk)
+            .await
+            .with_context(|| format!("error while writing to file {}", part.display()))?;
+        downloaded += chunk.len() as u64;
+        on_progress(downloaded, total_size);
+        if let Some(limiter) = limiter {
+            limiter.consume(chunk.len() as u64).await;
+        }
+    }
+    file.flush()
+        .await
+        .with_context(|| format!("failed to flush file {}", part.display()))?;
+    drop(file);
+
+    finish_part(&part, &validator_path, dest).await
+}
+
+/// Exponential backoff starting at 500ms, with up to 50% random jitter
+fn backoff_delay(attempt: u32) -> Duration {
+    let base = 500u64 << attempt.saturating_sub(1).min(6);
+    let jitter = rand::thread_rng().gen_range(0..=base / 2);
+    Duration::from_millis(base + jitter)
+}
+
+/// [`download_file`] with up to `retries` more attempts on failure. Progress
+/// restarts from where each attempt resumed, so it never exceeds the total.
+/// When the server asks to retry later, it is waited for as long as it says,
+/// without using up the attempts, up to [`MAX_RETRY_AFTER_WAIT`] in total.
+pub async fn download_file_with_retries(
+    downloader: &dyn Downloader,
+    url: &str,
+    dest: &Path,
+    partial_dir: &Path,
+    retries: u32,
+    limiter: Option<&RateLimiter>,
+    mut on_progress: impl FnMut(u64, Option<u64>),
+) -> Result<()> {
+    let mut attempt = 0;
+    let mut waited = Duration::ZERO;
+    loop {
+        match download_file(
+            downloader,
+            url,
+            dest,
+            partial_dir,
+            limiter,
+            &mut on_progress,
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is::<RetryAfter>() => {
+                // a date in the past must not retry in a busy loop
+                let delay = e
+                    .downcast_ref::<RetryAfter>()
+                    .map_or(Duration::ZERO, |r| r.delay)
+                    .max(Duration::from_secs(1));
+                if waited + delay > MAX_RETRY_AFTER_WAIT {
+                    return Err(e.context(format!(
+                        "the mirror is busy, not waiting more than {}s for it",
+                        MAX_RETRY_AFTER_WAIT.as_secs()
+                    )));
+                }
+                waited += delay;
+                log::debug!("{:#}, waiting", e);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) if attempt < retries && !e.is::<NotFound>() => {
+                attempt += 1;
+                let delay = backoff_delay(attempt);
+                log::debug!(
+                    "retrying {} ({}/{}) in {:?}: {:#}",
+                    url,
+                    attempt,
+                    retries,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Files of a toolchain release as (item, path relative to the mirror root,
+/// url), where item is the path relative to the moon home. They are listed by
+/// the `files.json` of the mirror if it has a valid one.
+fn download_items(
+    client: &reqwest::blocking::Client,
+    root: &str,
+    os_arch: &str,
+    components: Option<&[String]>,
+) -> Vec<(String, String, String)> {
+    // only for showing what would be downloaded, installing checks the list
+    let listed = get_text(
+        client,
+        &format!("{}/{}", root, files::files_json_path(os_arch)),
+    )
+    .ok()
+    .and_then(|content| files::parse_files(&content).ok());
+    let listed = listed.unwrap_or_else(|| files::builtin_files(os_arch));
+    files::select_components(listed, components)
+        .into_iter()
+        .map(|file| {
+            let url = format!("{}/{}", root, file.path);
+            (file.item, file.path, url)
+        })
+        .collect()
+}
+
+/// Size of `url` reported by a HEAD request, if the mirror supports it
+fn head_size(client: &reqwest::blocking::Client, url: &str) -> Option<u64> {
+    if let Some(path) = local_path(url) {
+        return std::fs::metadata(path).ok().map(|m| m.len());
+    }
+    client
+        .head(url)
+        .send()
+        .ok()
+        .filter(|resp| resp.status().is_success())
+        .and_then(|resp| decoded_length(resp.headers()))
+}
+
+/// Total size of a release, `None` if any file's size is unknown
+pub fn total_download_size(
+    client: &reqwest::blocking::Client,
+    root: &str,
+    os_arch: &str,
+    components: Option<&[String]>,
+) -> Option<u64> {
+    download_items(client, root, os_arch, components)
+        .iter()
+        .map(|(_, _, url)| head_size(client, url))
+        .sum()
+}
+
+/// Print what an upgrade from `root` would download, asking the mirror for
+/// the sizes without downloading anything
+pub fn print_download_plan(
+    client: &reqwest::blocking::Client,
+    home: &Path,
+    root: &str,
+    os_arch: &str,
+    components: Option<&[String]>,
+    out: Output,
+) -> Result<i32> {
+    if !out.json {
+        println!("Would download to {}:", home.display());
+    }
+    let mut total = Some(0);
+    for (item, _, url) in download_items(client, root, os_arch, components) {
+        let size = head_size(client, &url);
+        total = total.zip(size).map(|(total, size)| total + size);
+        if out.json {
+            out.event(&UpgradeEvent::PlannedDownload {
+                name: &item,
+                url: &url,
+                size,
+            });
+        } else {
+            let size = size.map_or("unknown size".to_string(), progress::format_bytes);
+            println!("  {} <- {} ({})", item, url, size);
+        }
+    }
+    if !out.json {
+        match total {
+            Some(total) => println!("Total: {}", progress::format_bytes(total)),
+            None => println!("Total: unknown"),
+        }
+    }
+    Ok(0)
+}
+
+/// How long to wait for the size of a file before downloading it anyway
+const SIZE_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Download the toolchain from the mirror at `root` to `dest`, with the
+/// partial downloads in `partial_dir`
+pub async fn download_toolchain(
+    root: &str,
+    items: &[ToolchainFile],
+    downloader: &dyn Downloader,
+    http: &HttpOptions,
+    dest: &Path,
+    partial_dir: &Path,
+    on_progress: &dyn Fn(ProgressEvent),
+    out: Output,
+) -> Result<()> {
+    let retries = http.retries;
+    // once an item had to be fetched from the other official mirror, the
+    // remaining ones go there first
+    let alternate_root = alternate_root(root);
+    let alternate_root = &alternate_root;
+    let switched = AtomicBool::new(false);
+    let switched = &switched;
+    let limiter = http.limit_rate.map(RateLimiter::new);
+    let limiter = limiter.as_ref();
+
+    let names = items
+        .iter()
+        .map(|file| file.item.clone())
+        .collect::<Vec<_>>();
+    on_progress(ProgressEvent::DownloadStarted { names: &names });
+
+    // with all sizes known up front, the total doesn't grow as downloads
+    // start. Mirrors that don't answer HEAD requests leave them unknown.
+    let sizes = futures::future::join_all(items.iter().map(|file| async move {
+        let url = format!("{}/{}", root, file.path);
+        tokio::time::timeout(SIZE_REQUEST_TIMEOUT, downloader.size(&url))
+            .await
+            .ok()
+            .flatten()
+    }))
+    .await;
+    for (file, size) in items.iter().zip(sizes) {
+        if let Some(total) = size {
+            on_progress(ProgressEvent::DownloadSize {
+                name: &file.item,
+                total,
+            });
+        }
+    }
+
+    for file in items {
+        out.debug(format!(
+            "Downloading {}/{} to {}",
+            root,
+            file.path,
+            dest.join(&file.item).display()
+        ));
+    }
+
+    let download_futures = items.iter().map(|file| async move {
+        let download_item = &file.item;
+        let mirror_path = &file.path;
+        let url = format!("{}/{}", root, mirror_path);
+        let filepath = dest.join(download_item);
+        if let Some(parent) = filepath.parent() {
+            if !parent.exists() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .context(format!("failed to create directory {}", parent.display()))?;
+            }
+        }
+        let mut on_download = |downloaded, total| {
+            on_progress(ProgressEvent::Download {
+                name: download_item,
+                downloaded,
+                total,
+            })
+        };
+
+        let mut candidates = vec![url.clone()];
+        if let Some(alternate_root) = alternate_root {
+            let alternate_url = format!("{}/{}", alternate_root, mirror_path);
+            if switched.load(Ordering::Relaxed) {
+                candidates.insert(0, alternate_url);
+            } else {
+                candidates.push(alternate_url);
+            }
+        }
+        let mut result = Ok(());
+        for (i, candidate) in candidates.iter().enumerate() {
+            if i > 0 {
+                on_progress(ProgressEvent::Message);
+                out.warn(&format!(
+                    "Warning: failed to download {} from {}, switching to {}",
+                    download_item,
+                    candidates[i - 1],
+                    candidate
+                ));
+            }
+            result = download_file_with_retries(
+                downloader,
+                candidate,
+                &filepath,
+                partial_dir,
+                retries,
+                limiter,
+                &mut on_download,
+            )
+            .await;
+            if result.is_ok() {
+                if *candidate != url {
+                    switched.store(true, Ordering::Relaxed);
+                }
+                break;
+            }
+        }
+        match result {
+            Err(e) if file.optional && e.is::<NotFound>() => {
+                on_progress(ProgressEvent::DownloadSkipped {
+                    name: download_item,
+                });
+                on_progress(ProgressEvent::Message);
+                out.warn(&format!(
+                    "Warning: {} is not available on the mirror, skipping it",
+                    download_item
+                ));
+                return Ok(());
+            }
+            result => result.with_context(|| format!("failed to download {}", download_item))?,
+        }
+
+        if out.verbose {
+            let size = std::fs::metadata(&filepath).map_or(0, |m| m.len());
+            on_progress(ProgressEvent::Message);
+            out.debug(format!(
+                "Downloaded {} ({})",
+                download_item,
+                progress::format_bytes(size)
+            ));
+        }
+        Ok::<(), anyhow::Error>(())
+    });
+
+    // Ctrl+C is handled by `interrupt`, partial downloads are kept to be
+    // resumed by the next run
+    stream::iter(download_futures)
+        .map(Ok)
+        .try_for_each_concurrent(http.concurrency(), |f| f)
+        .await?;
+    on_progress(ProgressEvent::DownloadFinished);
+    Ok(())
+}
+
+#[test]
+fn test_download_concurrency_limit() {
+    use super::http_client_builder;
+    use super::test_server::{self, Response};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    let active = Arc::new(AtomicUsize::new(0));
+    let max_active = Arc::new(AtomicUsize::new(0));
+    let addr = {
+        let active = Arc::clone(&active);
+        let max_active = Arc::clone(&max_active);
+        test_server::serve(move |_| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            max_active.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            // leave before responding so the count never exceeds what the client runs
+            active.fetch_sub(1, Ordering::SeqCst);
+            Response::new("200 OK", "hello")
+        })
+    };
+
+    let http = HttpOptions {
+        jobs: 2,
+        ..Default::default()
+    };
+    let client = http_client_builder(&http).unwrap().build().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let urls: Vec<String> = (0..8)
+        .map(|i| format!("http://{}/file{}", addr, i))
+        .collect();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+        .block_on(
+            stream::iter(
+                urls.iter().enumerate().map(|(i, url)| {
+                    let client = client.clone();
+                    let dest = dir.path().join(format!("file{}", i));
+                    let partial_dir = dir.path().join("downloads");
+                    async move {
+                        download_file(&client, url, &dest, &partial_dir, None, |_, _| {}).await
+                    }
+                }),
+            )
+            .map(Ok)
+            .try_for_each_concurrent(http.concurrency(), |f| f),
+        )
+        .unwrap();
+
+    assert_eq!(2, max_active.load(Ordering::SeqCst));
+    assert_eq!(None, HttpOptions::default().concurrency());
+}
+
+#[test]
+fn test_download_local_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let content = b"0123456789".repeat(LOCAL_CHUNK_SIZE / 4);
+    let source = dir.path().join("core.zip");
+    std::fs::write(&source, &content).unwrap();
+    let url = reqwest::Url::from_file_path(&source).unwrap().to_string();
+    assert_eq!(Some(source.clone()), local_path(&url));
+    assert_eq!(None, local_path("https://cli.moonbitlang.com/core.zip"));
+
+    let client = reqwest::Client::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let missing = format!("{}.missing", url);
+    let err = rt.block_on(client.fetch(&missing, None)).err().unwrap();
+    assert!(err.is::<NotFound>());
+
+    // keep the first half as if an earlier attempt had stopped there
+    let partial_dir = dir.path().join("partial");
+    let validator = rt.block_on(client.fetch(&url, None)).unwrap().validator;
+    let part = partial_path(&partial_dir, &url).unwrap();
+    std::fs::write(&part, &content[..content.len() / 2]).unwrap();
+    std::fs::write(format!("{}.validator", part.display()), validator.unwrap()).unwrap();
+
+    let dest = dir.path().join("downloaded.zip");
+    let mut progress = vec![];
+    rt.block_on(download_file(
+        &client,
+        &url,
+        &dest,
+        &partial_dir,
+        None,
+        |downloaded, total| progress.push((downloaded, total)),
+    ))
+    .unwrap();
+    assert_eq!(content, std::fs::read(&dest).unwrap());
+    let total = Some(content.len() as u64);
+    assert_eq!(Some(&((content.len() / 2) as u64, total)), progress.first());
+    assert_eq!(Some(&(content.len() as u64, total)), progress.last());
+}
+
+#[test]
+fn test_download_complete_part() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = "https://cli.moonbitlang.com/core.zip";
+    let content = b"0123456789".to_vec();
+    let downloader = MemoryDownloader([(url.to_string(), content.clone())].into());
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let partial_dir = dir.path().join("partial");
+    let part = partial_path(&partial_dir, url).unwrap();
+    let validator_path = format!("{}.validator", part.display());
+    let dest = dir.path().join("downloaded.zip");
+    let download = |part_content: &[u8]| {
+        std::fs::write(&part, part_content).unwrap();
+        std::fs::write(&validator_path, "\"memory\"").unwrap();
+        let _ = std::fs::remove_file(&dest);
+        let mut progress = vec![];
+        rt.block_on(download_file(
+            &downloader,
+            url,
+            &dest,
+            &partial_dir,
+            None,
+            |downloaded, total| progress.push((downloaded, total)),
+        ))
+        .unwrap();
+        assert_eq!(content, std::fs::read(&dest).unwrap());
+        assert!(!part.exists());
+        assert!(!Path::new(&validator_path).exists());
+        progress
+    };
+
+    // the server answers 416 when all of it was received before
+    let err = rt
+        .block_on(downloader.fetch(url, Some((10, "\"memory\""))))
+        .err()
+        .unwrap();
+    assert_eq!(
+        Some(10),
+        err.downcast_ref::<RangeNotSatisfiable>().unwrap().length
+    );
+    assert_eq!(vec![(10, Some(10))], download(&content));
+
+    // a part longer than the file is thrown away
+    let progress = download(b"0123456789abcdef");
+    assert_eq!(Some(&(0, Some(10))), progress.first());
+    assert_eq!(Some(&(10, Some(10))), progress.last());
+}
+
+#[test]
+fn test_download_gzip_response() {
+    use super::test_server::{self, Response};
+    use std::io::Write;
+
+    let content = b"moonc 0.1.20240828\n".repeat(100);
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(&content).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let addr = test_server::serve(move |_| {
+        Response::new("200 OK", compressed.clone()).header("Content-Encoding", "gzip")
+    });
+
+    let client = reqwest::Client::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let url = format!("http://{}/moonc", addr);
+    // the compressed size would be wrong for the decoded file
+    assert_eq!(None, rt.block_on(client.size(&url)));
+
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("moonc");
+    let mut progress = vec![];
+    rt.block_on(download_file(
+        &client,
+        &url,
+        &dest,
+        &dir.path().join("partial"),
+        None,
+        |downloaded, total| progress.push((downloaded, total)),
+    ))
+    .unwrap();
+    assert_eq!(content, std::fs::read(&dest).unwrap());
+    assert_eq!(Some(&(content.len() as u64, None)), progress.last());
+}
+
+#[test]
+fn test_parse_retry_after() {
+    assert_eq!(Some(Duration::from_secs(120)), parse_retry_after("120"));
+    assert_eq!(
+        Some(Duration::ZERO),
+        parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT")
+    );
+    let later = (chrono::Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+    let delay = parse_retry_after(&later).unwrap();
+    assert!(delay > Duration::from_secs(80) && delay <= Duration::from_secs(90));
+    assert_eq!(None, parse_retry_after("soon"));
+}
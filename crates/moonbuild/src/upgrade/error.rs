@@ -0,0 +1,92 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Failures of an upgrade, by the step that failed, so callers can tell a
+//! flaky network from a tampered download
+
+use super::{INTERRUPTED_EXIT_CODE, NETWORK_ERROR_EXIT_CODE, VERIFICATION_ERROR_EXIT_CODE};
+use std::fmt::Display;
+
+/// Each variant keeps the underlying error as its source, `{:#}` prints the
+/// whole chain
+#[derive(Debug, thiserror::Error)]
+pub enum UpgradeError {
+    /// Reaching the mirrors or downloading from them failed
+    #[error("failed to download the toolchain")]
+    Network(#[source] anyhow::Error),
+    /// The files don't match the checksums, or the checksums their signature
+    #[error("failed to verify the toolchain")]
+    Verification(#[source] anyhow::Error),
+    #[error("failed to extract the toolchain")]
+    Extraction(#[source] anyhow::Error),
+    #[error("failed to bundle the core")]
+    Bundle(#[source] anyhow::Error),
+    /// Reading or writing the moon home failed
+    #[error("failed to install the toolchain")]
+    Io(#[source] anyhow::Error),
+    /// Ctrl+C was pressed while the toolchain was being swapped
+    #[error("upgrade interrupted by Ctrl+C")]
+    Interrupted(#[source] anyhow::Error),
+    /// No toolchain is built for this platform, or the downloaded one doesn't
+    /// run on it
+    #[error("the toolchain is not available for this platform")]
+    UnsupportedPlatform(#[source] anyhow::Error),
+    /// Invalid options and everything else
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl UpgradeError {
+    /// Exit code of `moon upgrade` failing with this error, `None` for the
+    /// one of any failed command
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            UpgradeError::Network(_) => Some(NETWORK_ERROR_EXIT_CODE),
+            UpgradeError::Verification(_) => Some(VERIFICATION_ERROR_EXIT_CODE),
+            UpgradeError::Interrupted(_) => Some(INTERRUPTED_EXIT_CODE),
+            _ => None,
+        }
+    }
+
+    /// Add `context` to the underlying error, keeping the kind
+    pub fn context(self, context: impl Display + Send + Sync + 'static) -> Self {
+        use UpgradeError::*;
+        match self {
+            Network(e) => Network(e.context(context)),
+            Verification(e) => Verification(e.context(context)),
+            Extraction(e) => Extraction(e.context(context)),
+            Bundle(e) => Bundle(e.context(context)),
+            Io(e) => Io(e.context(context)),
+            Interrupted(e) => Interrupted(e.context(context)),
+            UnsupportedPlatform(e) => UnsupportedPlatform(e.context(context)),
+            Other(e) => Other(e.context(context)),
+        }
+    }
+}
+
+#[test]
+fn test_upgrade_error_context() {
+    let e = UpgradeError::Network(anyhow::anyhow!("connection reset"))
+        .context("failed to install toolchain 20240828");
+    assert!(matches!(e, UpgradeError::Network(_)));
+    assert_eq!(Some(NETWORK_ERROR_EXIT_CODE), e.exit_code());
+    assert_eq!(
+        "failed to download the toolchain: failed to install toolchain 20240828: connection reset",
+        format!("{:#}", anyhow::Error::from(e))
+    );
+}
@@ -0,0 +1,355 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! The files of a toolchain release. Mirrors may list them in
+//! `<os_arch>/files.json`, so that a release can add or drop a file without a
+//! new moon. Without it, the files known to this moon are installed.
+
+use super::checksum::{Checksums, SHA256SUMS};
+use super::output::Output;
+use super::{mirror_path, TOOLCHAIN_ITEMS};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Component, Path};
+
+pub const FILES_JSON: &str = "files.json";
+
+/// Binaries every release has in `bin`, with the flag printing their
+/// version. The upgrade runs them to bundle the core and to check the new
+/// toolchain, and `moon doctor` checks them.
+pub const REQUIRED_BINARIES: [(&str, &str); 3] = [
+    ("moon", "version"),
+    ("moonc", "-v"),
+    ("moonrun", "--version"),
+];
+
+/// Items of [`REQUIRED_BINARIES`], e.g. `bin/moon`
+pub fn required_items() -> impl Iterator<Item = String> {
+    REQUIRED_BINARIES
+        .iter()
+        .map(|(name, _)| format!("bin/{}", name))
+}
+
+/// Every release has one of these, extracted to `lib/core`
+const CORE_ARCHIVES: [&str; 3] = ["core.zip", "core.tar.gz", "core.tar.zst"];
+
+/// Not every platform has these, so they are skipped when the mirror
+/// doesn't have them
+const OPTIONAL_ITEMS: [&str; 2] = ["bin/moondoc", "bin/moon_cove_report"];
+
+/// A file of a toolchain release
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ToolchainFile {
+    /// Path relative to the moon home, e.g. `bin/moon`. Archives are
+    /// extracted to `lib` instead, like `core.zip` to `lib/core`.
+    pub item: String,
+    /// Path relative to the mirror root, e.g. `ubuntu_x86/bin/moon`. Files
+    /// other than archives may be compressed with zstd, with a `.zst`
+    /// extension.
+    pub path: String,
+    /// Installed with mode 755 instead of 644
+    #[serde(default)]
+    pub executable: bool,
+    /// Skipped if the mirror doesn't have it, instead of failing the upgrade
+    #[serde(default)]
+    pub optional: bool,
+}
+
+/// How an archive of the toolchain is packed, told by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    /// Smaller and faster to produce than zip, for unix-like platforms
+    TarGz,
+    TarZst,
+}
+
+impl ToolchainFile {
+    /// The format of the file if it is an archive, `None` for other files
+    pub fn archive(&self) -> Option<ArchiveFormat> {
+        if self.item.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else if self.item.ends_with(".tar.gz") {
+            Some(ArchiveFormat::TarGz)
+        } else if self.item.ends_with(".tar.zst") {
+            Some(ArchiveFormat::TarZst)
+        } else {
+            None
+        }
+    }
+
+    /// The component the file belongs to: the binary for files in `bin`,
+    /// `core` for the core and the item itself for the others
+    pub fn component(&self) -> &str {
+        if CORE_ARCHIVES.contains(&self.item.as_str()) {
+            "core"
+        } else {
+            self.item.strip_prefix("bin/").unwrap_or(&self.item)
+        }
+    }
+
+    /// Whether the mirror serves the file compressed with zstd, so that it
+    /// has to be decompressed once verified
+    pub fn is_zstd(&self) -> bool {
+        self.archive().is_none() && self.path.ends_with(".zst")
+    }
+}
+
+#[derive(Deserialize)]
+struct FilesJson {
+    files: Vec<ToolchainFile>,
+}
+
+/// Fail if one of `components` has no file in `files`
+pub fn check_components(files: &[ToolchainFile], components: &[String]) -> Result<()> {
+    let known = files
+        .iter()
+        .map(|file| file.component())
+        .collect::<Vec<_>>();
+    if let Some(unknown) = components
+        .iter()
+        .find(|component| !known.contains(&component.as_str()))
+    {
+        bail!(
+            "unknown component `{}`, the toolchain has {}",
+            unknown,
+            known.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The files of `components`, or all of them for `None`
+pub fn select_components(
+    files: Vec<ToolchainFile>,
+    components: Option<&[String]>,
+) -> Vec<ToolchainFile> {
+    match components {
+        Some(components) => files
+            .into_iter()
+            .filter(|file| components.iter().any(|c| c == file.component()))
+            .collect(),
+        None => files,
+    }
+}
+
+/// Path of the list of files for `os_arch`, relative to the mirror root
+pub fn files_json_path(os_arch: &str) -> String {
+    format!("{}/{}", os_arch, FILES_JSON)
+}
+
+/// The files known to this moon, for mirrors without a `files.json`
+pub fn builtin_files(os_arch: &str) -> Vec<ToolchainFile> {
+    TOOLCHAIN_ITEMS
+        .iter()
+        .map(|item| ToolchainFile {
+            item: item.to_string(),
+            path: mirror_path(item, os_arch),
+            executable: item.starts_with("bin/"),
+            optional: OPTIONAL_ITEMS.contains(item),
+        })
+        .collect()
+}
+
+/// Whether `path` is relative and stays inside the directory it is relative to
+fn is_contained(path: &str) -> bool {
+    let path = Path::new(path);
+    !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Parse a `files.json`, rejecting paths outside of the moon home or the
+/// mirror and lists without the files every release has
+pub fn parse_files(content: &str) -> Result<Vec<ToolchainFile>> {
+    let files = serde_json_lenient::from_str::<FilesJson>(content)
+        .with_context(|| format!("malformed {}", FILES_JSON))?
+        .files;
+    let mut items = HashSet::new();
+    for file in &files {
+        if !is_contained(&file.item) || !is_contained(&file.path) {
+            bail!(
+                "{} puts {} at `{}`, outside of the toolchain",
+                FILES_JSON,
+                file.path,
+                file.item
+            );
+        }
+        if !items.insert(file.item.as_str()) {
+            bail!("{} lists `{}` twice", FILES_JSON, file.item);
+        }
+    }
+    if let Some(missing) = required_items().find(|item| !items.contains(item.as_str())) {
+        bail!("{} doesn't list `{}`", FILES_JSON, missing);
+    }
+    match CORE_ARCHIVES
+        .iter()
+        .filter(|archive| items.contains(**archive))
+        .count()
+    {
+        0 => bail!(
+            "{} doesn't list `{}`",
+            FILES_JSON,
+            CORE_ARCHIVES.join("` or `")
+        ),
+        1 => {}
+        _ => bail!("{} lists more than one archive of the core", FILES_JSON),
+    }
+    if let Some(file) = files.iter().find(|file| {
+        file.optional
+            && (required_items().any(|item| item == file.item)
+                || CORE_ARCHIVES.contains(&file.item.as_str()))
+    }) {
+        bail!("{} marks `{}` as optional", FILES_JSON, file.item);
+    }
+    Ok(files)
+}
+
+/// The files to install: those in `files_json`, the staged `files.json` of
+/// the mirror, or the built-in ones if the mirror has none. As it decides
+/// where each file goes, with `checksums` it is only used if its checksum is
+/// there and matches.
+pub fn toolchain_files(
+    os_arch: &str,
+    files_json: &Path,
+    checksums: Option<&Checksums>,
+    out: Output,
+) -> Result<Vec<ToolchainFile>> {
+    let content = match std::fs::read_to_string(files_json) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(builtin_files(os_arch)),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read {}", files_json.display()))
+        }
+    };
+    let path = files_json_path(os_arch);
+    if let Some(checksums) = checksums {
+        if !checksums.contains(&path) {
+            out.warn(&format!(
+                "Warning: {} is not in {}, installing the files known to this moon",
+                path, SHA256SUMS
+            ));
+            return Ok(builtin_files(os_arch));
+        }
+        checksums.verify(&path, files_json)?;
+    }
+    out.debug(format!("Installing the files listed in {}", path));
+    parse_files(&content)
+}
+
+/// The files a directory laid out like the mirror should have, from its
+/// `files.json` if it has a valid one. Only for checking the directory up
+/// front, [`toolchain_files`] decides what is installed.
+pub fn listed_files(dir: &Path, os_arch: &str) -> Vec<ToolchainFile> {
+    std::fs::read_to_string(dir.join(files_json_path(os_arch)))
+        .ok()
+        .and_then(|content| parse_files(&content).ok())
+        .unwrap_or_else(|| builtin_files(os_arch))
+}
+
+#[test]
+fn test_parse_files() {
+    let files = |entries: &[&str]| {
+        let entries = entries
+            .iter()
+            .map(|item| {
+                format!(
+                    r#"{{"item": "{}", "path": "ubuntu_x86/{}", "executable": true, "optional": {}}}"#,
+                    item,
+                    item,
+                    *item == "bin/moonx"
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        parse_files(&format!(r#"{{"files": [{}]}}"#, entries))
+    };
+    let listed = files(&[
+        "bin/moon",
+        "bin/moonc",
+        "bin/moonrun",
+        "core.zip",
+        "bin/moonx",
+    ])
+    .unwrap();
+    assert_eq!(5, listed.len());
+    assert_eq!("ubuntu_x86/bin/moonx", listed[4].path);
+    assert!(listed[4].executable);
+    assert!(listed[4].optional && !listed[0].optional);
+
+    let tarball = files(&["bin/moon", "bin/moonc", "bin/moonrun", "core.tar.gz"]).unwrap();
+    assert_eq!(Some(ArchiveFormat::TarGz), tarball[3].archive());
+    assert_eq!(None, tarball[0].archive());
+    let compressed = ToolchainFile {
+        path: "ubuntu_x86/lib/libtcc1.a.zst".to_string(),
+        ..tarball[0].clone()
+    };
+    assert!(compressed.is_zstd() && !tarball[0].is_zstd());
+    assert!(files(&["bin/moon", "bin/moonc", "bin/moonrun"]).is_err());
+    assert!(files(&[
+        "bin/moon",
+        "bin/moonc",
+        "bin/moonrun",
+        "core.zip",
+        "core.tar.gz"
+    ])
+    .is_err());
+    assert!(files(&["bin/moon", "bin/moonc", "core.zip"]).is_err());
+    assert!(files(&[
+        "bin/moon",
+        "bin/moonc",
+        "bin/moonrun",
+        "core.zip",
+        "../evil"
+    ])
+    .is_err());
+    assert!(files(&[
+        "bin/moon",
+        "bin/moonc",
+        "bin/moonrun",
+        "core.zip",
+        "/etc/evil"
+    ])
+    .is_err());
+    assert!(files(&[
+        "bin/moon",
+        "bin/moonc",
+        "bin/moonrun",
+        "core.zip",
+        "bin/moon"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_select_components() {
+    let files = builtin_files("ubuntu_x86");
+    let components = ["moon".to_string(), "core".to_string()];
+    let selected = select_components(files.clone(), Some(&components))
+        .into_iter()
+        .map(|file| file.item)
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["bin/moon", "core.zip"], selected);
+    assert_eq!("internal/tcc", files[12].component());
+    assert_eq!("include/moonbit.h", files[0].component());
+    assert_eq!(files.len(), select_components(files.clone(), None).len());
+    assert!(check_components(&files, &components).is_ok());
+    assert!(check_components(&files, &["moonx".to_string()]).is_err());
+}
@@ -0,0 +1,797 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Swapping a staged toolchain into the moon home, restoring the previous one
+//! when anything goes wrong on the way
+
+use super::files::REQUIRED_BINARIES;
+use super::normalize_path;
+use super::output::Output;
+use anyhow::{bail, Context, Result};
+use moonutil::common::MOONBITLANG_CORE;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Bundle the core extracted to `staging`, a directory laid out like the moon
+/// home, with the moon and moonc staged next to it. The installed toolchain
+/// is neither used nor touched. `moon bundle` is killed if it runs longer
+/// than `timeout`.
+pub fn bundle_core(staging: &Path, timeout: Option<Duration>, out: Output) -> Result<()> {
+    let bin = staging.join("bin");
+    let moon = bin.join("moon");
+    let core_dir = staging.join("lib").join("core");
+    // the core has to be bundled by the compiler it was released with, never
+    // by the installed one
+    if !moon.is_file() || !core_dir.is_dir() {
+        bail!(
+            "the new moon and core have to be staged in {} before bundling",
+            staging.display()
+        );
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let path =
+        std::env::join_paths(std::iter::once(bin.clone()).chain(std::env::split_paths(&path)))
+            .context("failed to set PATH for bundling")?;
+    out.debug(format!(
+        "Running MOON_HOME={} PATH={}:$PATH {} bundle --all --source-dir {}",
+        staging.display(),
+        bin.display(),
+        moon.display(),
+        core_dir.display()
+    ));
+
+    let mut bundle = std::process::Command::new(&moon);
+    bundle
+        .env("MOON_HOME", staging)
+        .env("PATH", &path)
+        .args(["bundle", "--all", "--source-dir"])
+        .arg(&core_dir);
+    // the output is shown as it comes with `verbose`, and with the error if
+    // bundling fails
+    let bundle = output_streamed(bundle, timeout, out)
+        .with_context(|| format!("failed to run {}", moon.display()))?;
+    if bundle.status.success() {
+        return Ok(());
+    }
+    // the diagnostics of moonc may be on either stream
+    let details = [&bundle.stderr, &bundle.stdout]
+        .into_iter()
+        .map(|output| String::from_utf8_lossy(output).trim().to_string())
+        .find(|output| !output.is_empty())
+        .map_or(String::new(), |output| format!(":\n{}", output));
+    match bundle.status.code() {
+        Some(code) => bail!("failed to compile core, exit code {}{}", code, details),
+        None => bail!("failed to bundle {}{}", MOONBITLANG_CORE, details),
+    }
+}
+
+/// How often a command with a timeout is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The output of `command`, which is also printed line by line as it comes
+/// when `verbose`. The command is killed if it runs longer than `timeout`.
+fn output_streamed(
+    mut command: std::process::Command,
+    timeout: Option<Duration>,
+    out: Output,
+) -> Result<std::process::Output> {
+    let mut child = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    fn forward(
+        pipe: impl std::io::Read + Send + 'static,
+        out: Output,
+    ) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut collected = vec![];
+            for line in std::io::BufRead::split(std::io::BufReader::new(pipe), b'\n') {
+                match line {
+                    Ok(line) => {
+                        if out.verbose {
+                            out.debug(String::from_utf8_lossy(&line).trim_end());
+                        }
+                        collected.extend_from_slice(&line);
+                        collected.push(b'\n');
+                    }
+                    Err(_) => break,
+                }
+            }
+            collected
+        })
+    }
+    let stdout = child.stdout.take().map(|pipe| forward(pipe, out));
+    let stderr = child.stderr.take().map(|pipe| forward(pipe, out));
+    let status = match timeout {
+        None => child.wait()?,
+        Some(timeout) => {
+            let start = Instant::now();
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if start.elapsed() >= timeout {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    // the output isn't waited for, processes started by the
+                    // command may still hold the pipes
+                    bail!("timed out after {}s", timeout.as_secs());
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    };
+    let collect = |handle: Option<std::thread::JoinHandle<Vec<u8>>>| {
+        handle
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default()
+    };
+    Ok(std::process::Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    })
+}
+
+#[test]
+fn test_bundle_core_requires_staged_toolchain() {
+    let staging = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(staging.path().join("lib/core")).unwrap();
+    let err = bundle_core(staging.path(), None, Output::default()).unwrap_err();
+    assert!(err.to_string().contains("staged"), "{}", err);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bundle_core_reports_stderr() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let staging = tempfile::tempdir().unwrap();
+    let bin = staging.path().join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::create_dir_all(staging.path().join("lib/core")).unwrap();
+    let moon = bin.join("moon");
+    std::fs::write(
+        &moon,
+        "#!/bin/sh\necho compiling\necho 'error: moonbit.h not found' >&2\nexit 3\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&moon, std::fs::Permissions::from_mode(0o755)).unwrap();
+    for verbose in [false, true] {
+        let out = Output {
+            quiet: true,
+            verbose,
+            ..Default::default()
+        };
+        let err = bundle_core(staging.path(), None, out)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("exit code 3"), "{}", err);
+        assert!(err.contains("moonbit.h not found"), "{}", err);
+    }
+
+    // a hanging bundle is killed
+    std::fs::write(&moon, "#!/bin/sh\nexec sleep 30\n").unwrap();
+    let start = Instant::now();
+    let err = bundle_core(
+        staging.path(),
+        Some(Duration::from_millis(300)),
+        Output {
+            quiet: true,
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("timed out"), "{:#}", err);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bundle_core_runs_only_bundle() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let staging = tempfile::tempdir().unwrap();
+    let bin = staging.path().join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::create_dir_all(staging.path().join("lib/core")).unwrap();
+    let moon = bin.join("moon");
+    let calls = staging.path().join("calls");
+    let write_moon = |stdout: &str, code: i32| {
+        std::fs::write(
+            &moon,
+            format!(
+                "#!/bin/sh\necho \"$1\" >> '{}'\necho '{}'\nexit {}\n",
+                calls.display(),
+                stdout,
+                code
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&moon, std::fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    let out = Output {
+        quiet: true,
+        ..Default::default()
+    };
+    write_moon("bundled", 0);
+    bundle_core(staging.path(), None, out).unwrap();
+    assert_eq!("bundle\n", std::fs::read_to_string(&calls).unwrap());
+
+    // diagnostics only printed to stdout are in the error
+    write_moon("error: type mismatch", 2);
+    let err = bundle_core(staging.path(), None, out)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("type mismatch"), "{}", err);
+}
+
+/// Versions reported by the binaries in `bin`, failing if any of them
+/// doesn't run, e.g. when it was built for another platform or libc
+pub fn binary_versions(bin: &Path) -> Result<Vec<String>> {
+    binary_versions_of(bin, &REQUIRED_BINARIES.map(|(name, _)| name))
+}
+
+/// [`binary_versions`] of the required binaries among `names`
+pub fn binary_versions_of(bin: &Path, names: &[&str]) -> Result<Vec<String>> {
+    REQUIRED_BINARIES
+        .iter()
+        .filter(|(name, _)| names.contains(name))
+        .map(|(name, flag)| {
+            let path = bin.join(name);
+            let output = std::process::Command::new(&path)
+                .arg(flag)
+                .output()
+                .with_context(|| format!("failed to run {}", path.display()))?;
+            if !output.status.success() {
+                bail!(
+                    "{} {} failed: {}",
+                    path.display(),
+                    flag,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .collect()
+}
+
+/// Drop the Mark-of-the-Web of a downloaded binary, which makes SmartScreen
+/// ask before running it
+#[cfg(windows)]
+pub fn remove_zone_identifier(path: &Path) -> std::io::Result<()> {
+    let mut stream = path.as_os_str().to_owned();
+    stream.push(":Zone.Identifier");
+    match std::fs::remove_file(&stream) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Whether Windows refused to replace a file because another process has it
+/// open
+#[cfg(windows)]
+fn is_sharing_violation(e: &std::io::Error) -> bool {
+    // ERROR_ACCESS_DENIED or ERROR_SHARING_VIOLATION
+    matches!(e.raw_os_error(), Some(5) | Some(32))
+}
+
+/// Rename the file at `path` to `<path>.old`, which Windows allows even for
+/// running executables, to free its name
+#[cfg(windows)]
+fn move_aside(path: &Path) -> std::io::Result<PathBuf> {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(".old");
+    let aside = PathBuf::from(aside);
+    // left over by an earlier upgrade
+    let _ = std::fs::remove_file(&aside);
+    std::fs::rename(path, &aside)?;
+    Ok(aside)
+}
+
+/// Remove `path`, or if it is still in use, ask Windows to remove it on the
+/// next reboot. That needs administrator rights, otherwise the file stays
+/// until [`move_aside`] replaces it in the next upgrade.
+#[cfg(windows)]
+fn remove_or_schedule(path: &Path) {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn MoveFileExW(existing: *const u16, new: *const u16, flags: u32) -> i32;
+    }
+    const MOVEFILE_DELAY_UNTIL_REBOOT: u32 = 0x4;
+
+    if std::fs::remove_file(path).is_ok() {
+        return;
+    }
+    let wide = path
+        .as_os_str()
+        .encode_wide()
+        .chain([0])
+        .collect::<Vec<u16>>();
+    // SAFETY: `wide` is a NUL-terminated path, and a null new name asks for
+    // the file to be deleted
+    if unsafe { MoveFileExW(wide.as_ptr(), std::ptr::null(), MOVEFILE_DELAY_UNTIL_REBOOT) } == 0 {
+        log::debug!("failed to schedule the removal of {}", path.display());
+    }
+}
+
+/// `path` with symlinks resolved, or only normalized if it doesn't exist
+fn resolve_path(path: &Path) -> PathBuf {
+    // unlike `std::fs::canonicalize`, no `\\?\` device paths on Windows
+    dunce::canonicalize(path).unwrap_or_else(|_| normalize_path(path))
+}
+
+/// Whether `path` is the running executable, which has to be replaced with
+/// `self_replace` instead of being moved. Either may be a symlink, e.g. when
+/// a version manager puts moon on PATH.
+pub fn is_current_exe(path: &Path) -> bool {
+    std::env::current_exe().is_ok_and(|exe| same_path(&exe, path))
+}
+
+/// Whether `a` and `b` are the same file or directory, through symlinks
+pub fn same_path(a: &Path, b: &Path) -> bool {
+    resolve_path(a) == resolve_path(b)
+}
+
+#[cfg(unix)]
+#[test]
+fn test_is_current_exe_through_symlink() {
+    let dir = tempfile::tempdir().unwrap();
+    let link = dir.path().join("moon");
+    std::os::unix::fs::symlink(std::env::current_exe().unwrap(), &link).unwrap();
+    assert!(is_current_exe(&link));
+    assert!(is_current_exe(&std::env::current_exe().unwrap()));
+    assert!(!is_current_exe(&dir.path().join("moonc")));
+}
+
+/// Where the previous moon is saved when moon replaces itself, e.g.
+/// `bin/moon.bak`, for `moon upgrade --revert`
+pub fn cli_backup_path(moon: &Path) -> PathBuf {
+    moon.with_extension("bak")
+}
+
+/// Put the moon saved by [`Install::replace`] back in place of `moon`
+pub fn revert_cli(moon: &Path) -> Result<()> {
+    let saved = cli_backup_path(moon);
+    if is_current_exe(moon) {
+        self_replace::self_replace(&saved)
+            .with_context(|| format!("failed to replace {}", moon.display()))?;
+        std::fs::remove_file(&saved)
+            .with_context(|| format!("failed to remove {}", saved.display()))
+    } else {
+        std::fs::rename(&saved, moon)
+            .with_context(|| format!("failed to move {} to {}", saved.display(), moon.display()))
+    }
+}
+
+#[test]
+fn test_revert_cli() {
+    let dir = tempfile::tempdir().unwrap();
+    let moon = dir.path().join("moon");
+    std::fs::write(&moon, "new").unwrap();
+    std::fs::write(dir.path().join("moon.bak"), "old").unwrap();
+    revert_cli(&moon).unwrap();
+    assert_eq!("old", std::fs::read_to_string(&moon).unwrap());
+    assert!(!cli_backup_path(&moon).exists());
+    assert!(revert_cli(&moon).is_err());
+}
+
+pub fn remove_path(path: &Path) -> std::io::Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+/// A temporary directory in `tmp`. Staged files are moved into place by a
+/// cheap rename when `tmp` is on the filesystem of the moon home, e.g. `tmp`
+/// in the home, and copied otherwise.
+pub fn staging_dir(tmp: &Path) -> Result<tempfile::TempDir> {
+    std::fs::create_dir_all(tmp)
+        .with_context(|| format!("failed to create directory {}", tmp.display()))?;
+    tempfile::tempdir_in(tmp)
+        .with_context(|| format!("failed to create a directory in {}", tmp.display()))
+}
+
+/// Whether a rename failed because the paths are on different filesystems
+fn is_cross_device(e: &std::io::Error) -> bool {
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(windows)]
+    let code = 17;
+    // EXDEV
+    #[cfg(not(windows))]
+    let code = 18;
+    e.raw_os_error() == Some(code)
+}
+
+/// Rename `src`, a file or a directory, to `dst`, or copy it to `dst` and
+/// remove it if they are on different filesystems
+fn move_path(src: &Path, dst: &Path) -> Result<()> {
+    match std::fs::rename(src, dst) {
+        Err(e) if is_cross_device(&e) => {
+            copy_path(src, dst)?;
+            remove_path(src).with_context(|| format!("failed to remove {}", src.display()))
+        }
+        moved => {
+            moved.with_context(|| format!("failed to move {} to {}", src.display(), dst.display()))
+        }
+    }
+}
+
+#[test]
+fn test_move_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let staged = dir.path().join("staging").join("core");
+    std::fs::create_dir_all(staged.join("builtin")).unwrap();
+    std::fs::write(staged.join("builtin").join("moon.pkg.json"), "{}").unwrap();
+    let dst = dir.path().join("lib").join("core");
+    std::fs::create_dir_all(dst.parent().unwrap()).unwrap();
+
+    move_path(&staged, &dst).unwrap();
+    assert!(!staged.exists());
+    assert_eq!(
+        "{}",
+        std::fs::read_to_string(dst.join("builtin").join("moon.pkg.json")).unwrap()
+    );
+    assert!(!is_cross_device(&std::io::Error::from(
+        std::io::ErrorKind::NotFound
+    )));
+}
+
+/// How to get past `e`, an error writing to `dir`
+fn unwritable_hint(dir: &Path, e: &std::io::Error) -> String {
+    // EROFS, `ErrorKind::ReadOnlyFilesystem` needs a newer Rust
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(30) {
+        return "it is on a read-only filesystem, pass `--prefix` to install somewhere else"
+            .to_string();
+    }
+    if e.kind() != std::io::ErrorKind::PermissionDenied {
+        return "pass `--prefix` to install somewhere else".to_string();
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let owner = dir
+            .ancestors()
+            .find_map(|dir| dir.metadata().ok())
+            .map(|metadata| metadata.uid());
+        if owner == Some(0) {
+            return format!(
+                "it is owned by root, likely from installing with sudo. Run `sudo chown -R \"$(whoami)\" {}` to take it back, or pass `--prefix` to install somewhere else",
+                dir.display()
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    "check its permissions, or pass `--prefix` to install somewhere else".to_string()
+}
+
+/// Fail early when nothing can be written to `dir`
+pub fn check_writable(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .and_then(|()| tempfile::tempfile_in(dir))
+        .map(drop)
+        .map_err(|e| {
+            let hint = unwritable_hint(dir, &e);
+            anyhow::Error::new(e).context(format!("{} is not writable, {}", dir.display(), hint))
+        })
+}
+
+/// Fail before anything is downloaded when the toolchain can't be installed
+/// to `home`. Entries are replaced by renames in their parent directories,
+/// which have to be writable too.
+pub fn check_home_writable(home: &Path) -> Result<()> {
+    check_writable(home)?;
+    for dir in ["bin", "lib"] {
+        let dir = home.join(dir);
+        if dir.is_dir() {
+            check_writable(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unwritable_hint() {
+    let dir = tempfile::tempdir().unwrap();
+    let hint = |e: std::io::Error| unwritable_hint(dir.path(), &e);
+    assert!(hint(std::io::Error::from_raw_os_error(30)).contains("read-only"));
+    assert_eq!(
+        "pass `--prefix` to install somewhere else",
+        hint(std::io::ErrorKind::Other.into())
+    );
+}
+
+/// Previous toolchains are kept in timestamped directories under this one in
+/// the moon home, e.g. `backups/20240828T120000`
+pub const BACKUPS_DIR: &str = "backups";
+
+/// Replaces entries of the moon home one by one. The previous entries are
+/// moved to a new backup directory instead of being deleted, so that
+/// [`Install::rollback`] can put them back.
+pub struct Install {
+    home: PathBuf,
+    backup: PathBuf,
+    /// How many backups [`Install::commit`] keeps
+    keep_backups: usize,
+    /// Replaced destinations, with where their previous content was moved
+    replaced: Vec<(PathBuf, Option<PathBuf>)>,
+    /// Locked files renamed out of the way, removed once done
+    #[cfg(windows)]
+    aside: Vec<PathBuf>,
+}
+
+impl Install {
+    pub fn new(home: &Path, keep_backups: usize) -> Result<Self> {
+        // inside the moon home, so moving files aside is a cheap rename on
+        // the same filesystem
+        let backups = home.join(BACKUPS_DIR);
+        let timestamp = chrono::Local::now().format("%Y%m%dT%H%M%S").to_string();
+        let mut backup = backups.join(&timestamp);
+        let mut n = 1;
+        while backup.exists() {
+            backup = backups.join(format!("{}-{}", timestamp, n));
+            n += 1;
+        }
+        std::fs::create_dir_all(&backup)
+            .with_context(|| format!("failed to create directory {}", backup.display()))?;
+        Ok(Install {
+            home: home.to_path_buf(),
+            backup,
+            keep_backups,
+            replaced: vec![],
+            #[cfg(windows)]
+            aside: vec![],
+        })
+    }
+
+    /// Where the previous entries are moved
+    pub fn backup_dir(&self) -> &Path {
+        &self.backup
+    }
+
+    /// Move `staged`, a file or a directory, to `rel` under the moon home
+    pub fn replace(&mut self, staged: &Path, rel: &str) -> Result<()> {
+        let dst = self.home.join(rel);
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        let replace_self = is_current_exe(&dst);
+
+        let backup = if dst.symlink_metadata().is_ok() {
+            let backup = self.backup.join(rel);
+            if let Some(parent) = backup.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create directory {}", parent.display()))?;
+            }
+            // the running executable stays in place until self_replace
+            let moved = if replace_self {
+                std::fs::copy(&dst, &backup).map(|_| ())
+            } else {
+                std::fs::rename(&dst, &backup)
+            };
+            // another process holding the file open, e.g. a wrapper that
+            // launched moon, doesn't prevent renaming it in place
+            #[cfg(windows)]
+            let moved = match moved {
+                Err(e) if !replace_self && is_sharing_violation(&e) => std::fs::copy(&dst, &backup)
+                    .and_then(|_| move_aside(&dst))
+                    .map(|aside| self.aside.push(aside)),
+                moved => moved,
+            };
+            moved.with_context(|| format!("failed to back up {}", dst.display()))?;
+            Some(backup)
+        } else {
+            None
+        };
+        self.replaced.push((dst.clone(), backup));
+
+        if replace_self {
+            // unlike the backup directory, this copy is neither pruned nor
+            // tied to the rest of the toolchain
+            let saved = cli_backup_path(&dst);
+            std::fs::copy(&dst, &saved)
+                .with_context(|| format!("failed to save {}", saved.display()))?;
+            let replaced = self_replace::self_replace(staged);
+            #[cfg(windows)]
+            let replaced = match replaced {
+                Err(e) if is_sharing_violation(&e) => move_aside(&dst).and_then(|aside| {
+                    self.aside.push(aside);
+                    std::fs::rename(staged, &dst)
+                }),
+                replaced => replaced,
+            };
+            replaced.with_context(|| format!("failed to replace {}", dst.display()))?;
+        } else {
+            move_path(staged, &dst)?;
+        }
+        Ok(())
+    }
+
+    /// Keep the new toolchain, and keep the previous one as a backup unless
+    /// nothing was replaced. Only the newest `keep_backups` backups are kept.
+    /// Returns the backup, `None` if it was removed.
+    pub fn commit(self) -> Result<Option<PathBuf>> {
+        #[cfg(windows)]
+        self.aside
+            .iter()
+            .for_each(|aside| remove_or_schedule(aside));
+        let keep =
+            self.keep_backups > 0 && self.replaced.iter().any(|(_, backup)| backup.is_some());
+        if !keep {
+            remove_path(&self.backup)
+                .with_context(|| format!("failed to remove {}", self.backup.display()))?;
+        }
+        prune_backups(&self.home.join(BACKUPS_DIR), self.keep_backups)?;
+        Ok(if keep { Some(self.backup) } else { None })
+    }
+
+    /// Keep the new entries and drop the backup of the replaced ones
+    pub fn discard_backup(self) -> Result<()> {
+        remove_path(&self.backup)
+            .with_context(|| format!("failed to remove {}", self.backup.display()))
+    }
+
+    /// Put the previous entries back, in reverse order. Returns the
+    /// destinations that couldn't be restored, whose previous content is left
+    /// in the backup directory.
+    pub fn rollback(self) -> Vec<PathBuf> {
+        let mut failed = vec![];
+        for (dst, backup) in self.replaced.into_iter().rev() {
+            let restored = match backup {
+                Some(backup) if is_current_exe(&dst) => self_replace::self_replace(&backup),
+                Some(backup) => match remove_path(&dst) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                    _ => std::fs::rename(&backup, &dst),
+                },
+                None => match remove_path(&dst) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                },
+            };
+            if let Err(e) = restored {
+                log::debug!("failed to restore {}: {}", dst.display(), e);
+                failed.push(dst);
+            }
+        }
+        #[cfg(windows)]
+        self.aside
+            .iter()
+            .for_each(|aside| remove_or_schedule(aside));
+        if failed.is_empty() {
+            let _ = remove_path(&self.backup);
+        }
+        failed
+    }
+}
+
+/// Backup directories, oldest first as their names sort by time
+fn list_backups(backups: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = match std::fs::read_dir(backups) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read {}", backups.display()));
+        }
+    };
+    entries.sort();
+    Ok(entries)
+}
+
+/// The backup made by the last upgrade
+pub fn latest_backup(home: &Path) -> Result<Option<PathBuf>> {
+    Ok(list_backups(&home.join(BACKUPS_DIR))?.pop())
+}
+
+/// Remove all but the newest `keep` backups
+fn prune_backups(backups: &Path, keep: usize) -> Result<()> {
+    let entries = list_backups(backups)?;
+    let outdated = entries.len().saturating_sub(keep);
+    for path in &entries[..outdated] {
+        std::fs::remove_dir_all(path)
+            .with_context(|| format!("failed to remove backup {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn copy_path(src: &Path, dst: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry.with_context(|| format!("failed to read {}", src.display()))?;
+        let target = dst.join(entry.path().strip_prefix(src)?);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("failed to create directory {}", target.display()))?;
+        } else {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create directory {}", parent.display()))?;
+            }
+            std::fs::copy(entry.path(), &target).with_context(|| {
+                format!(
+                    "failed to copy {} to {}",
+                    entry.path().display(),
+                    target.display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Put the `entries` of `backup`, paths relative to the moon home, back in
+/// place. The backup is copied first, so it is still there if restoring
+/// fails, and removed once the toolchain it contains is restored.
+pub fn restore_backup(home: &Path, backup: &Path, entries: &[&str]) -> Result<()> {
+    let staging = staging_dir(&home.join("tmp"))?;
+    let entries = entries
+        .iter()
+        .filter(|entry| backup.join(entry).exists())
+        .copied()
+        .collect::<Vec<_>>();
+    for entry in entries.iter() {
+        copy_path(&backup.join(entry), &staging.path().join(entry))?;
+    }
+
+    let mut install = Install::new(home, 0)?;
+    let restored = entries
+        .iter()
+        .try_for_each(|entry| install.replace(&staging.path().join(entry), entry));
+    if let Err(e) = restored {
+        let failed = install.rollback();
+        if !failed.is_empty() {
+            let failed = failed
+                .iter()
+                .map(|p| format!("  {}", p.display()))
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(e.context(format!(
+                "failed to roll back, these files may be broken:\n{}",
+                failed
+            )));
+        }
+        return Err(e);
+    }
+    install.discard_backup()?;
+    remove_path(backup).with_context(|| format!("failed to remove {}", backup.display()))
+}
+
+#[test]
+fn test_prune_backups() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["20240801T000000", "20240901T000000", "20240828T120000"] {
+        std::fs::create_dir(dir.path().join(name)).unwrap();
+    }
+    prune_backups(dir.path(), 2).unwrap();
+    let mut left = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    left.sort();
+    assert_eq!(vec!["20240828T120000", "20240901T000000"], left);
+}
@@ -0,0 +1,86 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Ctrl+C handling of the upgrade. Exiting skips destructors, so the staging
+//! directory is removed by the handler, and while the installed toolchain is
+//! being replaced the interrupt is deferred until it can be restored.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static STAGING: Mutex<Option<PathBuf>> = Mutex::new(None);
+static DEFERRED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_handler() -> anyhow::Result<()> {
+    ctrlc::set_handler(handle_ctrlc)?;
+    Ok(())
+}
+
+fn handle_ctrlc() {
+    if DEFERRED.load(Ordering::SeqCst) {
+        if !INTERRUPTED.swap(true, Ordering::SeqCst) {
+            eprintln!("Interrupted, restoring the previous toolchain ...");
+        }
+        return;
+    }
+    if let Some(dir) = STAGING.lock().ok().and_then(|mut staging| staging.take()) {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+    // like `dialoguer_ctrlc_handler`, with the exit code of the upgrade
+    let _ = console::Term::stdout().show_cursor();
+    std::process::exit(super::INTERRUPTED_EXIT_CODE);
+}
+
+/// Removes the directory on Ctrl+C until dropped
+pub struct StagingGuard(());
+
+pub fn remove_on_interrupt(dir: &Path) -> StagingGuard {
+    if let Ok(mut staging) = STAGING.lock() {
+        *staging = Some(dir.to_path_buf());
+    }
+    StagingGuard(())
+}
+
+impl Drop for StagingGuard {
+    fn drop(&mut self) {
+        if let Ok(mut staging) = STAGING.lock() {
+            *staging = None;
+        }
+    }
+}
+
+/// Ctrl+C only sets [`interrupted`] until dropped
+pub struct DeferGuard(());
+
+pub fn defer() -> DeferGuard {
+    DEFERRED.store(true, Ordering::SeqCst);
+    DeferGuard(())
+}
+
+impl Drop for DeferGuard {
+    fn drop(&mut self) {
+        DEFERRED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Whether Ctrl+C was pressed while it was deferred
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,115 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! `toolchain.json` in the moon home, recording what the last upgrade
+//! installed and where it came from, e.g. for bug reports
+
+use super::InstalledComponent;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+pub const TOOLCHAIN_MANIFEST: &str = "toolchain.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolchainManifest {
+    /// The mirror or the directory the toolchain was installed from
+    pub source: String,
+    /// When the upgrade finished, in RFC 3339
+    pub installed_at: String,
+    pub components: Vec<ManifestComponent>,
+}
+
+/// An installed component, like [`InstalledComponent`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestComponent {
+    pub name: String,
+    pub version: Option<String>,
+    pub date: Option<String>,
+}
+
+impl ToolchainManifest {
+    /// The toolchain with `components` just installed from `source`
+    pub fn new(source: &str, components: Vec<InstalledComponent>) -> Self {
+        ToolchainManifest {
+            source: source.to_string(),
+            installed_at: chrono::Local::now().to_rfc3339(),
+            components: components
+                .into_iter()
+                .map(|component| ManifestComponent {
+                    name: component.name.to_string(),
+                    version: component.version,
+                    date: component.date,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The manifest in `home`, `None` if there is none or it is malformed
+pub fn read_manifest(home: &Path) -> Option<ToolchainManifest> {
+    let content = std::fs::read_to_string(home.join(TOOLCHAIN_MANIFEST)).ok()?;
+    serde_json_lenient::from_str(&content).ok()
+}
+
+/// Replace the manifest in `home`, so that it is never half written
+pub fn write_manifest(home: &Path, manifest: &ToolchainManifest) -> Result<()> {
+    let path = home.join(TOOLCHAIN_MANIFEST);
+    let content = serde_json_lenient::to_string_pretty(manifest)?;
+    let mut file = tempfile::NamedTempFile::new_in(home)
+        .with_context(|| format!("failed to create a file in {}", home.display()))?;
+    std::io::Write::write_all(&mut file, content.as_bytes())
+        .and_then(|()| file.as_file().sync_all())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    file.persist(&path)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Drop the manifest once it no longer describes the toolchain, e.g. after
+/// a rollback
+pub fn remove_manifest(home: &Path) -> Result<()> {
+    let path = home.join(TOOLCHAIN_MANIFEST);
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_toolchain_manifest() {
+    let home = tempfile::tempdir().unwrap();
+    assert_eq!(None, read_manifest(home.path()));
+
+    let manifest = ToolchainManifest::new(
+        "https://cli.moonbitlang.com",
+        vec![InstalledComponent {
+            name: "moon",
+            version: Some("moon 0.1.20240828 (901ac075 2024-08-28)".to_string()),
+            date: Some("20240828".to_string()),
+        }],
+    );
+    write_manifest(home.path(), &manifest).unwrap();
+    assert_eq!(Some(manifest), read_manifest(home.path()));
+
+    remove_manifest(home.path()).unwrap();
+    remove_manifest(home.path()).unwrap();
+    assert_eq!(None, read_manifest(home.path()));
+}
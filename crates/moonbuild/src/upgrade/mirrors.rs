@@ -0,0 +1,328 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Choosing the mirror the toolchain is downloaded from
+
+use super::download::local_path;
+use super::output::Output;
+use super::{extract_date, fetch_version_items, http_client_builder, HttpOptions};
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use moonutil::common::VersionItems;
+use std::path::Path;
+use std::time::Duration;
+
+pub const OFFICIAL_MIRRORS: [&str; 2] =
+    ["https://cli.moonbitlang.com", "https://cli.moonbitlang.cn"];
+
+/// The same location on the other official mirror, `None` for custom mirrors
+pub fn alternate_root(root: &str) -> Option<String> {
+    let (i, rest) = OFFICIAL_MIRRORS
+        .iter()
+        .enumerate()
+        .find_map(|(i, mirror)| Some((i, root.strip_prefix(mirror)?)))?;
+    Some(format!("{}{}", OFFICIAL_MIRRORS[1 - i], rest))
+}
+
+#[test]
+fn test_alternate_root() {
+    assert_eq!(
+        Some("https://cli.moonbitlang.cn/nightly".to_string()),
+        alternate_root("https://cli.moonbitlang.com/nightly")
+    );
+    assert_eq!(
+        Some("https://cli.moonbitlang.com".to_string()),
+        alternate_root("https://cli.moonbitlang.cn")
+    );
+    assert_eq!(None, alternate_root("https://mirror.example.com"));
+}
+
+/// The first of `candidates` whose `probe` succeeds within `timeout`. The
+/// probes run concurrently and the slower ones are dropped once one succeeds.
+async fn first_responding<'a, T: Copy + 'a>(
+    candidates: &[T],
+    timeout: Duration,
+    probe: impl Fn(T) -> BoxFuture<'a, bool>,
+) -> Option<T> {
+    if candidates.is_empty() {
+        return None;
+    }
+    let probes = candidates.iter().map(|&candidate| {
+        let probe = probe(candidate);
+        Box::pin(async move {
+            if probe.await {
+                Ok(candidate)
+            } else {
+                Err(())
+            }
+        })
+    });
+    match tokio::time::timeout(timeout, futures::future::select_ok(probes)).await {
+        Ok(Ok((first, _))) => Some(first),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_first_responding() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    // (delay in ms, whether the probe succeeds)
+    let race = |probes: &[(u64, bool)], timeout: u64| {
+        rt.block_on(first_responding(
+            &probes.iter().copied().enumerate().collect::<Vec<_>>(),
+            Duration::from_millis(timeout),
+            |(_, (delay, ok))| {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    ok
+                })
+            },
+        ))
+        .map(|(i, _)| i)
+    };
+    assert_eq!(Some(1), race(&[(200, true), (10, true)], 1000));
+    assert_eq!(Some(0), race(&[(200, true), (10, false)], 1000));
+    assert_eq!(None, race(&[(200, true), (10, false)], 50));
+    assert_eq!(None, race(&[(10, false), (10, false)], 1000));
+}
+
+/// Probe the official mirrors concurrently and pick the first one that
+/// serves `version.json`, or the `.cn` one if none does within `timeout`
+pub fn check_connectivity(opts: &HttpOptions, timeout: Duration) -> anyhow::Result<&'static str> {
+    let client = http_client_builder(opts)?
+        .timeout(timeout)
+        .build()
+        .context("Failed to create HTTP client")?;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("failed to start the async runtime")?;
+
+    let client = &client;
+    let fastest = rt.block_on(first_responding(&OFFICIAL_MIRRORS, timeout, |root| {
+        Box::pin(probe_mirror(client, root))
+    }));
+    // none of them responded in time, keep the previous default
+    Ok(fastest.unwrap_or(OFFICIAL_MIRRORS[1]))
+}
+
+/// Whether the mirror at `root` serves `version.json`
+async fn probe_mirror(client: &reqwest::Client, root: &str) -> bool {
+    let url = format!("{}/version.json", root);
+    if let Some(path) = local_path(&url) {
+        return path.is_file();
+    }
+    client
+        .head(url)
+        .send()
+        .await
+        .is_ok_and(|resp| resp.status().is_success())
+}
+
+/// Mirrors to try before the official ones, separated by commas
+const MOON_UPGRADE_MIRRORS: &str = "MOON_UPGRADE_MIRRORS";
+
+/// File in the moon home listing mirrors to try before the official ones,
+/// unless `MOON_UPGRADE_MIRRORS` is set
+const MIRRORS_FILE: &str = "mirrors.txt";
+
+/// Parse a list of mirrors, one per line or separated by commas. Blank lines
+/// and lines starting with `#` are ignored.
+fn parse_mirror_list(content: &str) -> Result<Vec<String>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|mirror| !mirror.is_empty())
+        .map(parse_mirror)
+        .collect()
+}
+
+#[test]
+fn test_parse_mirror_list() {
+    assert_eq!(
+        vec![
+            "https://a.example.com",
+            "https://b.example.com/moonbit",
+            "http://c.example.com"
+        ],
+        parse_mirror_list(
+            "# in order\nhttps://a.example.com/\n\n  https://b.example.com/moonbit,http://c.example.com\n"
+        )
+        .unwrap()
+    );
+    assert!(parse_mirror_list("https://a.example.com\nb.example.com").is_err());
+}
+
+/// The mirrors configured in `MOON_UPGRADE_MIRRORS` or the mirrors file of
+/// `home`, with where they are configured
+pub fn configured_mirrors(home: &Path) -> Result<Option<(Vec<String>, String)>> {
+    if let Some(list) = std::env::var(MOON_UPGRADE_MIRRORS)
+        .ok()
+        .filter(|list| !list.trim().is_empty())
+    {
+        let mirrors = parse_mirror_list(&list)
+            .with_context(|| format!("invalid {}", MOON_UPGRADE_MIRRORS))?;
+        return Ok(Some((mirrors, MOON_UPGRADE_MIRRORS.to_string())));
+    }
+    let path = home.join(MIRRORS_FILE);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mirrors =
+        parse_mirror_list(&content).with_context(|| format!("invalid {}", path.display()))?;
+    Ok(Some((mirrors, path.display().to_string())).filter(|(mirrors, _)| !mirrors.is_empty()))
+}
+
+/// The first of `mirrors` serving `version.json` within `timeout`. They are
+/// probed at once, but a mirror that responds later still wins over the ones
+/// after it.
+pub fn first_healthy_mirror(
+    opts: &HttpOptions,
+    mirrors: &[String],
+    timeout: Duration,
+) -> anyhow::Result<Option<String>> {
+    let client = http_client_builder(opts)?
+        .timeout(timeout)
+        .build()
+        .context("Failed to create HTTP client")?;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("failed to start the async runtime")?;
+    let healthy = rt.block_on(futures::future::join_all(
+        mirrors.iter().map(|root| probe_mirror(&client, root)),
+    ));
+    Ok(mirrors
+        .iter()
+        .zip(healthy)
+        .find(|(_, healthy)| *healthy)
+        .map(|(root, _)| root.clone()))
+}
+
+pub const MOON_UPGRADE_MIRROR: &str = "MOON_UPGRADE_MIRROR";
+
+/// Validate a user supplied mirror and strip the trailing slash
+pub fn parse_mirror(mirror: &str) -> Result<String> {
+    let url =
+        reqwest::Url::parse(mirror).with_context(|| format!("invalid mirror url `{}`", mirror))?;
+    if !matches!(url.scheme(), "http" | "https" | "file") {
+        bail!(
+            "invalid mirror url `{}`, only http, https and file are supported",
+            mirror
+        );
+    }
+    if url.scheme() == "file" && url.to_file_path().is_err() {
+        bail!("invalid mirror url `{}`, the path must be absolute", mirror);
+    }
+    Ok(mirror.trim_end_matches('/').to_string())
+}
+
+#[test]
+fn test_parse_mirror() {
+    assert_eq!(
+        "https://mirror.example.com/moonbit",
+        parse_mirror("https://mirror.example.com/moonbit/").unwrap()
+    );
+    assert_eq!(
+        "file:///tmp/mirror",
+        parse_mirror("file:///tmp/mirror/").unwrap()
+    );
+    assert!(parse_mirror("ftp://mirror.example.com").is_err());
+    assert!(parse_mirror("mirror.example.com").is_err());
+}
+
+pub fn download_page(root: &str) -> &'static str {
+    if root.contains("moonbitlang.cn") {
+        "https://www.moonbitlang.cn/download"
+    } else {
+        "https://www.moonbitlang.com/download"
+    }
+}
+
+/// Days the release on one official mirror may trail the other before it is
+/// worth mentioning; they usually catch up within hours
+const MIRROR_LAG_WARN_DAYS: i64 = 2;
+
+/// How many days the moon release in `latest` is older than the one in
+/// `alternate`, `None` if it isn't behind or either date is unknown
+fn mirror_lag_days(latest: &VersionItems, alternate: &VersionItems) -> Option<i64> {
+    let moon_date = |items: &VersionItems| {
+        let item = items.items.iter().find(|item| item.name == "moon")?;
+        let date = extract_date(&item.version).ok()?;
+        chrono::NaiveDate::parse_from_str(&date, "%Y%m%d").ok()
+    };
+    let lag = (moon_date(alternate)? - moon_date(latest)?).num_days();
+    (lag > 0).then_some(lag)
+}
+
+#[test]
+fn test_mirror_lag_days() {
+    let items = |version: &str| VersionItems {
+        items: vec![moonutil::common::VersionItem {
+            name: "moon".to_string(),
+            version: version.to_string(),
+            path: None,
+        }],
+    };
+    let old = items("moon 0.1.20240828 (abcdef0 2024-08-28)");
+    let new = items("moon 0.1.20240903 (abcdef0 2024-09-03)");
+    assert_eq!(Some(6), mirror_lag_days(&old, &new));
+    assert_eq!(None, mirror_lag_days(&new, &old));
+    assert_eq!(None, mirror_lag_days(&new, &new));
+    assert_eq!(None, mirror_lag_days(&items("unknown"), &new));
+}
+
+/// Warn if the other official mirror has a newer moon than `latest` from
+/// `root`. This is only advice, so failing to reach the other mirror is
+/// ignored.
+pub fn warn_if_mirror_lags(
+    client: &reqwest::blocking::Client,
+    root: &str,
+    latest: &VersionItems,
+    out: Output,
+) {
+    let alternate = match alternate_root(root) {
+        Some(alternate) => alternate,
+        None => return,
+    };
+    let url = format!("{}/version.json", alternate);
+    out.debug(format!("Comparing with {}", url));
+    let other = match fetch_version_items(client, &url, out) {
+        Ok((items, _)) => items,
+        Err(e) => {
+            out.debug(format!("Skipped the comparison: {}", e));
+            return;
+        }
+    };
+    if let Some(days) = mirror_lag_days(latest, &other) {
+        if days >= MIRROR_LAG_WARN_DAYS {
+            out.warn(&format!(
+                "Warning: {} is {} days behind {}, pass `--mirror {}` for the latest toolchain",
+                root, days, alternate, alternate
+            ));
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Human and machine-readable (`--json`) reporting of the upgrade
+
+use colored::Colorize;
+use serde::Serialize;
+
+/// Newline-delimited JSON events printed with `--json`
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum UpgradeEvent<'a> {
+    /// The mirror that is used, and how it was chosen
+    Connectivity { mirror: &'a str, source: &'a str },
+    /// Releases available on the channel, for `--list`
+    Versions { versions: &'a [String] },
+    /// Whether the installed toolchain is outdated, `None` if unknown
+    VersionCheck { upgrade: Option<bool> },
+    /// A file `--dry-run` would download, `size` if the mirror reports it
+    PlannedDownload {
+        name: &'a str,
+        url: &'a str,
+        size: Option<u64>,
+    },
+    /// A file `--dry-run` would copy from the directory of `--from-path`
+    PlannedCopy { name: &'a str, path: &'a str },
+    /// What `--dry-run` would restore with `--revert` or `--rollback`, the
+    /// saved moon or a backup of the toolchain
+    PlannedRestore { backup: &'a str, version: &'a str },
+    /// `total` is `None` when the mirror doesn't send the size
+    FileProgress {
+        name: &'a str,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// An archive of the toolchain is being extracted in the staging directory
+    Extracting { name: &'a str },
+    /// The new toolchain is bundling the core
+    Bundling,
+    Summary {
+        status: UpgradeStatus,
+        old_versions: Vec<ComponentVersion>,
+        new_versions: Vec<ComponentVersion>,
+    },
+    /// `--revert` or `--rollback` put back the saved toolchain
+    Restored { version: &'a str },
+    /// The upgrade has finished, the last event printed
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradeStatus {
+    Installed,
+    UpToDate,
+    Cancelled,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComponentVersion {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl ComponentVersion {
+    pub fn from_pairs(versions: Vec<(&str, Option<String>)>) -> Vec<Self> {
+        versions
+            .into_iter()
+            .map(|(name, version)| ComponentVersion {
+                name: name.to_string(),
+                version,
+            })
+            .collect()
+    }
+}
+
+/// Where messages go. With `json`, stdout only carries events; with `quiet`,
+/// nothing is printed to it. Warnings go to stderr in both cases.
+/// `verbose` adds the details of each step on stderr.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Output {
+    pub quiet: bool,
+    pub json: bool,
+    pub verbose: bool,
+}
+
+impl Output {
+    /// Whether informational human text is printed
+    pub fn human(&self) -> bool {
+        !self.quiet && !self.json
+    }
+
+    pub fn warn(&self, msg: &str) {
+        if self.json || self.quiet {
+            eprintln!("{}", msg);
+        } else {
+            println!("{}", msg.bold());
+        }
+    }
+
+    /// Details like urls and paths, for debugging a failed upgrade. They go
+    /// to the log unless `verbose`.
+    pub fn debug(&self, msg: impl std::fmt::Display) {
+        if self.verbose {
+            eprintln!("{}", msg.to_string().dimmed());
+        } else {
+            log::debug!("{}", msg);
+        }
+    }
+
+    pub fn event(&self, event: &UpgradeEvent) {
+        if self.json {
+            // these are plain data, serializing can't fail
+            if let Ok(line) = serde_json_lenient::to_string(event) {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_upgrade_event_json() {
+    let line = |event: &UpgradeEvent| serde_json_lenient::to_string(event).unwrap();
+    assert_eq!(
+        r#"{"event":"extracting","name":"core.zip"}"#,
+        line(&UpgradeEvent::Extracting { name: "core.zip" })
+    );
+    assert_eq!(r#"{"event":"bundling"}"#, line(&UpgradeEvent::Bundling));
+    assert_eq!(
+        r#"{"event":"file_progress","name":"bin/moon","downloaded":1,"total":null}"#,
+        line(&UpgradeEvent::FileProgress {
+            name: "bin/moon",
+            downloaded: 1,
+            total: None
+        })
+    );
+    assert_eq!(
+        r#"{"event":"planned_restore","backup":"/home/.moon/bin/moon.old","version":"moon 0.1.20240828"}"#,
+        line(&UpgradeEvent::PlannedRestore {
+            backup: "/home/.moon/bin/moon.old",
+            version: "moon 0.1.20240828"
+        })
+    );
+}
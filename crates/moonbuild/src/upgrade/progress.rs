@@ -0,0 +1,671 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Progress of the concurrent toolchain downloads and of extracting the
+//! core, reported as [`ProgressEvent`]s and shown on the terminal by default
+
+use super::output::{Output, UpgradeEvent};
+use console::Term;
+use indexmap::IndexMap;
+use moonutil::common::MOONBITLANG_CORE;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+
+/// Redrawing on every chunk of every download would keep the terminal busy
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Without a terminal, e.g. in CI logs, a progress line is printed whenever
+/// another 5% is done or this much time has passed
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+const LOG_STEP: f64 = 5.0;
+
+/// The speed is measured over windows of this length and smoothed
+const SPEED_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What [`install_toolchain`](super::install_toolchain) is doing, for
+/// showing its progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent<'a> {
+    /// Downloading these toolchain files starts
+    DownloadStarted { names: &'a [String] },
+    /// The size of a file, if the mirror tells it before the download
+    DownloadSize { name: &'a str, total: u64 },
+    /// Bytes of a file downloaded so far, `total` is `None` if the mirror
+    /// doesn't tell the size
+    Download {
+        name: &'a str,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// An optional file the mirror doesn't have, which isn't downloaded
+    DownloadSkipped { name: &'a str },
+    /// A message is printed next, progress drawn on the terminal has to make
+    /// room for it
+    Message,
+    /// All files are downloaded
+    DownloadFinished,
+    /// Entries of an archive extracted so far
+    Extract {
+        name: &'a str,
+        extracted: usize,
+        total: usize,
+    },
+    /// The archive is extracted
+    ExtractFinished { name: &'a str },
+    /// The new toolchain starts bundling the core
+    Bundling,
+}
+
+/// Shows [`ProgressEvent`]s on the terminal, or as `--json` events
+pub struct TerminalProgress {
+    output: Output,
+    download: Option<DownloadProgress>,
+    extract: Option<ExtractProgress>,
+}
+
+impl TerminalProgress {
+    pub fn new(output: Output) -> Self {
+        TerminalProgress {
+            output,
+            download: None,
+            extract: None,
+        }
+    }
+
+    pub fn handle(&mut self, event: ProgressEvent) {
+        let output = self.output;
+        match event {
+            ProgressEvent::DownloadStarted { names } => {
+                self.download = Some(DownloadProgress::new(names, output))
+            }
+            ProgressEvent::Download {
+                name,
+                downloaded,
+                total,
+            } => {
+                if let Some(download) = &mut self.download {
+                    download.update(name, downloaded, total);
+                }
+            }
+            ProgressEvent::DownloadSize { name, total } => {
+                if let Some(download) = &mut self.download {
+                    download.set_size(name, total);
+                }
+            }
+            ProgressEvent::DownloadSkipped { name } => {
+                if let Some(download) = &mut self.download {
+                    download.skip(name);
+                }
+            }
+            ProgressEvent::Message => {
+                if let Some(download) = &mut self.download {
+                    download.clear();
+                }
+            }
+            ProgressEvent::DownloadFinished => {
+                if let Some(mut download) = self.download.take() {
+                    download.finish();
+                }
+            }
+            ProgressEvent::Extract {
+                name,
+                extracted,
+                total,
+            } => self
+                .extract
+                .get_or_insert_with(|| ExtractProgress::new(name, output))
+                .update(extracted, total),
+            ProgressEvent::ExtractFinished { .. } => {
+                if let Some(mut extract) = self.extract.take() {
+                    extract.finish();
+                }
+            }
+            ProgressEvent::Bundling => {
+                if output.human() {
+                    println!("Bundling {} ...", MOONBITLANG_CORE);
+                }
+                output.event(&UpgradeEvent::Bundling);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Hidden,
+    /// `file_progress` events
+    Json,
+    /// One bar per file, on a terminal
+    Bars,
+    /// Periodic percentage lines, without a terminal
+    Lines,
+}
+
+#[derive(Default)]
+struct FileProgress {
+    started: bool,
+    /// `None` until known, and for the whole download when the mirror
+    /// doesn't send a `Content-Length`
+    total_size: Option<u64>,
+    downloaded: u64,
+    /// Downloaded bytes in the last `file_progress` event
+    reported: Option<u64>,
+}
+
+/// One bar per file when stdout is a terminal, so a stuck download is easy to
+/// spot, and periodic newline-terminated percentage lines otherwise
+pub struct DownloadProgress {
+    term: Term,
+    output: Output,
+    files: IndexMap<String, FileProgress>,
+    style: Style,
+    drawn_lines: usize,
+    /// Time and percentage of the last line printed without a terminal
+    last_logged: Option<(Instant, f64)>,
+    last_draw: Option<Instant>,
+    /// Time and total downloaded bytes when the speed was last measured
+    last_sample: Option<(Instant, u64)>,
+    /// Aggregate speed in bytes per second
+    speed: f64,
+}
+
+impl DownloadProgress {
+    pub fn new<'a>(names: impl IntoIterator<Item = &'a String>, output: Output) -> Self {
+        let term = Term::stdout();
+        let style = if output.json {
+            Style::Json
+        } else if output.quiet {
+            Style::Hidden
+        } else if term.is_term() {
+            Style::Bars
+        } else {
+            Style::Lines
+        };
+        DownloadProgress {
+            term,
+            output,
+            files: names
+                .into_iter()
+                .map(|name| (name.clone(), FileProgress::default()))
+                .collect(),
+            style,
+            drawn_lines: 0,
+            last_logged: None,
+            last_draw: None,
+            last_sample: None,
+            speed: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, name: &str, downloaded: u64, total_size: Option<u64>) {
+        if let Some(file) = self.files.get_mut(name) {
+            file.started = true;
+            file.downloaded = downloaded;
+            // keep the size known up front if the response doesn't tell it
+            file.total_size = total_size.or(file.total_size);
+        }
+        let now = Instant::now();
+        self.sample_speed(now);
+        if self.style == Style::Hidden {
+            return;
+        }
+        if self
+            .last_draw
+            .is_some_and(|last| now.duration_since(last) < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw = Some(now);
+        self.draw();
+    }
+
+    /// Downloaded and total bytes, the total is `None` if a started download
+    /// has an unknown size
+    fn totals(&self) -> (u64, Option<u64>) {
+        self.files.values().fold((0, Some(0)), |(cur, total), p| {
+            let size = match (p.started, p.total_size) {
+                (_, Some(size)) => Some(size),
+                (false, None) => Some(0),
+                (true, None) => None,
+            };
+            (cur + p.downloaded, total.zip(size).map(|(a, b)| a + b))
+        })
+    }
+
+    fn sample_speed(&mut self, now: Instant) {
+        let (cur, _) = self.totals();
+        match self.last_sample {
+            // the first sample only sets the baseline, it may include bytes
+            // of resumed downloads
+            None => self.last_sample = Some((now, cur)),
+            Some((at, bytes)) => {
+                let elapsed = now.duration_since(at);
+                if elapsed >= SPEED_SAMPLE_INTERVAL {
+                    let speed = cur.saturating_sub(bytes) as f64 / elapsed.as_secs_f64();
+                    self.speed = if self.speed == 0.0 {
+                        speed
+                    } else {
+                        0.3 * speed + 0.7 * self.speed
+                    };
+                    self.last_sample = Some((now, cur));
+                }
+            }
+        }
+    }
+
+    /// Aggregate speed and estimated time remaining
+    fn speed_and_eta(&self) -> String {
+        if self.speed == 0.0 {
+            return String::new();
+        }
+        let speed = format!(", {}/s", format_bytes(self.speed as u64));
+        match self.totals() {
+            (cur, Some(total)) => {
+                let eta = total.saturating_sub(cur) as f64 / self.speed;
+                format!(
+                    "{}, ETA {}",
+                    speed,
+                    format_duration(Duration::from_secs_f64(eta))
+                )
+            }
+            (_, None) => speed,
+        }
+    }
+
+    /// Count `total` bytes for a file that hasn't started yet, so that the
+    /// aggregate progress is right from the start
+    pub fn set_size(&mut self, name: &str, total: u64) {
+        if let Some(file) = self.files.get_mut(name) {
+            if !file.started {
+                file.total_size = Some(total);
+            }
+        }
+    }
+
+    /// Stop showing a file that won't be downloaded
+    pub fn skip(&mut self, name: &str) {
+        self.files.shift_remove(name);
+    }
+
+    /// Erase the progress so a message can be printed, it is drawn again on
+    /// the next update
+    pub fn clear(&mut self) {
+        if self.style == Style::Bars {
+            let _ = self.term.clear_last_lines(self.drawn_lines);
+            self.drawn_lines = 0;
+        }
+    }
+
+    /// Leave the final state on screen
+    pub fn finish(&mut self) {
+        match self.style {
+            Style::Hidden => {}
+            Style::Json => self.emit_events(),
+            Style::Bars => self.draw(),
+            Style::Lines => {
+                if !self.last_logged.is_some_and(|(_, logged)| logged >= 100.0) {
+                    self.log_line(true);
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self) {
+        match self.style {
+            Style::Hidden => return,
+            Style::Json => {
+                self.emit_events();
+                return;
+            }
+            Style::Bars => {}
+            Style::Lines => {
+                self.log_line(false);
+                return;
+            }
+        }
+
+        let (cur, total) = self.totals();
+
+        let name_width = self.files.keys().map(|name| name.len()).max().unwrap_or(0);
+        let lines = self
+            .files
+            .iter()
+            .map(|(name, p)| {
+                let size = if p.started {
+                    format!(
+                        "{}/{}",
+                        format_bytes(p.downloaded),
+                        format_size(p.total_size)
+                    )
+                } else {
+                    "waiting".to_string()
+                };
+                format!(
+                    "{:<name_width$} [{}] {}",
+                    name,
+                    bar(p.downloaded, p.total_size),
+                    size,
+                    name_width = name_width
+                )
+            })
+            .chain(std::iter::once(format!(
+                "Downloading {}{}",
+                total_progress(cur, total),
+                self.speed_and_eta()
+            )))
+            .collect::<Vec<_>>();
+        let _ = self.term.clear_last_lines(self.drawn_lines);
+        for line in &lines {
+            let _ = self.term.write_line(line);
+        }
+        self.drawn_lines = lines.len();
+    }
+
+    /// Throttling may skip some updates, so every file that changed since
+    /// the last event is reported
+    fn emit_events(&mut self) {
+        for (name, p) in self.files.iter_mut() {
+            if p.reported != Some(p.downloaded) {
+                self.output.event(&UpgradeEvent::FileProgress {
+                    name,
+                    downloaded: p.downloaded,
+                    total: p.total_size,
+                });
+                p.reported = Some(p.downloaded);
+            }
+        }
+    }
+
+    fn log_line(&mut self, force: bool) {
+        let (cur, total) = self.totals();
+        let now = Instant::now();
+        let (percent, msg) = match total {
+            Some(0) => return,
+            Some(total) => {
+                let percent = cur.min(total) as f64 / total as f64 * 100.0;
+                (Some(percent), total_progress(cur, Some(total)))
+            }
+            None => (None, format_bytes(cur)),
+        };
+        // without the size, lines are only printed periodically
+        let due = match self.last_logged {
+            None => true,
+            Some((at, logged)) => {
+                percent.is_some_and(|percent| percent >= logged + LOG_STEP)
+                    || now.duration_since(at) >= LOG_INTERVAL
+            }
+        };
+        if force || due {
+            let msg = format!("Downloading {}{}", msg, self.speed_and_eta());
+            let _ = self.term.write_line(&msg);
+            self.last_logged = Some((now, percent.unwrap_or(0.0)));
+        }
+    }
+}
+
+/// A single bar of the extracted entries of an archive on a terminal, and
+/// periodic lines otherwise. Hidden with `--verbose`, which lists the entries
+/// instead, and with `--json`, which has an `extracting` event.
+pub struct ExtractProgress {
+    term: Term,
+    style: Style,
+    name: String,
+    total: usize,
+    extracted: usize,
+    drawn: bool,
+    last_draw: Option<Instant>,
+    /// Time and percentage of the last line printed without a terminal
+    last_logged: Option<(Instant, f64)>,
+}
+
+impl ExtractProgress {
+    pub fn new(name: &str, output: Output) -> Self {
+        let term = Term::stdout();
+        let style = if output.json || output.quiet || output.verbose {
+            Style::Hidden
+        } else if term.is_term() {
+            Style::Bars
+        } else {
+            Style::Lines
+        };
+        ExtractProgress {
+            term,
+            style,
+            name: name.to_string(),
+            total: 0,
+            extracted: 0,
+            drawn: false,
+            last_draw: None,
+            last_logged: None,
+        }
+    }
+
+    pub fn update(&mut self, extracted: usize, total: usize) {
+        self.extracted = extracted;
+        self.total = total;
+        let now = Instant::now();
+        match self.style {
+            Style::Hidden | Style::Json => {}
+            Style::Bars => {
+                if !self
+                    .last_draw
+                    .is_some_and(|last| now.duration_since(last) < REDRAW_INTERVAL)
+                {
+                    self.last_draw = Some(now);
+                    self.draw();
+                }
+            }
+            Style::Lines => self.log_line(now, false),
+        }
+    }
+
+    /// Leave the final state on screen
+    pub fn finish(&mut self) {
+        match self.style {
+            Style::Hidden | Style::Json => {}
+            Style::Bars => self.draw(),
+            Style::Lines => {
+                if !self.last_logged.is_some_and(|(_, logged)| logged >= 100.0) {
+                    self.log_line(Instant::now(), true);
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self) {
+        if self.drawn {
+            let _ = self.term.clear_last_lines(1);
+        }
+        let _ = self
+            .term
+            .write_line(&extract_line(&self.name, self.extracted, self.total));
+        self.drawn = true;
+    }
+
+    fn log_line(&mut self, now: Instant, force: bool) {
+        if self.total == 0 {
+            return;
+        }
+        let percent = self.extracted as f64 / self.total as f64 * 100.0;
+        let due = match self.last_logged {
+            None => true,
+            Some((at, logged)) => {
+                percent >= logged + LOG_STEP || now.duration_since(at) >= LOG_INTERVAL
+            }
+        };
+        if force || due {
+            let _ = self.term.write_line(&format!(
+                "Extracting {} {:.1}% ({}/{} files)",
+                self.name, percent, self.extracted, self.total
+            ));
+            self.last_logged = Some((now, percent));
+        }
+    }
+}
+
+fn extract_line(name: &str, extracted: usize, total: usize) -> String {
+    format!(
+        "Extracting {} [{}] {}/{} files",
+        name,
+        bar(extracted as u64, Some(total as u64)),
+        extracted,
+        total
+    )
+}
+
+/// A bar filled up to the downloaded part, or a marker moving along with
+/// the downloaded bytes when the size is unknown
+fn bar(downloaded: u64, total_size: Option<u64>) -> String {
+    let total_size = match total_size {
+        Some(total_size) => total_size,
+        None => {
+            let pos = (downloaded / (64 * 1024)) as usize % BAR_WIDTH;
+            return format!("{}#{}", "-".repeat(pos), "-".repeat(BAR_WIDTH - pos - 1));
+        }
+    };
+    let filled = if total_size == 0 {
+        0
+    } else {
+        (downloaded.min(total_size) as f64 / total_size as f64 * BAR_WIDTH as f64) as usize
+    };
+    format!("{}{}", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+/// Percentage with the downloaded and total bytes, until no size is known
+/// yet
+fn total_progress(downloaded: u64, total_size: Option<u64>) -> String {
+    match (downloaded, total_size) {
+        (0, Some(0)) => "(starting...)".to_string(),
+        (_, Some(total)) if total > 0 => format!(
+            "{:.1}% ({}/{})",
+            downloaded.min(total) as f64 / total as f64 * 100.0,
+            format_bytes(downloaded),
+            format_bytes(total)
+        ),
+        _ => format!("{}/{}", format_bytes(downloaded), format_size(total_size)),
+    }
+}
+
+/// [`format_bytes`], or `?` when the size is unknown
+fn format_size(bytes: Option<u64>) -> String {
+    bytes.map_or("?".to_string(), format_bytes)
+}
+
+/// Format a size with binary units, e.g. `1.5 MiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Format a duration as `42s` or `3m 05s`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+#[test]
+fn test_progress_format() {
+    assert_eq!("512 B", format_bytes(512));
+    assert_eq!("1.5 KiB", format_bytes(1536));
+    assert_eq!("20.0 MiB", format_bytes(20 * 1024 * 1024));
+    assert_eq!(
+        format!("{}{}", "#".repeat(15), "-".repeat(15)),
+        bar(50, Some(100))
+    );
+    assert_eq!("-".repeat(BAR_WIDTH), bar(0, Some(0)));
+    assert_eq!(
+        format!("--#{}", "-".repeat(BAR_WIDTH - 3)),
+        bar(2 * 64 * 1024, None)
+    );
+    assert_eq!("?", format_size(None));
+    assert_eq!("(starting...)", total_progress(0, Some(0)));
+    assert_eq!("512 B/?", total_progress(512, None));
+    assert_eq!("0.0% (0 B/1.0 KiB)", total_progress(0, Some(1024)));
+    assert_eq!("50.0% (1.0 KiB/2.0 KiB)", total_progress(1024, Some(2048)));
+    assert_eq!("42s", format_duration(Duration::from_secs(42)));
+    assert_eq!("3m 05s", format_duration(Duration::from_secs(185)));
+    assert_eq!(
+        format!("Extracting core.zip [{}] 0/0 files", "-".repeat(BAR_WIDTH)),
+        extract_line("core.zip", 0, 0)
+    );
+}
+
+#[test]
+fn test_download_totals() {
+    let names = ["bin/moon".to_string(), "core.zip".to_string()];
+    let hidden = Output {
+        quiet: true,
+        ..Output::default()
+    };
+    let mut progress = DownloadProgress::new(&names, hidden);
+    assert_eq!((0, Some(0)), progress.totals());
+
+    progress.set_size("bin/moon", 100);
+    progress.set_size("core.zip", 300);
+    assert_eq!((0, Some(400)), progress.totals());
+    // a response without the size keeps the one known before
+    progress.update("core.zip", 50, None);
+    assert_eq!((50, Some(400)), progress.totals());
+    progress.update("bin/moon", 10, Some(100));
+    progress.set_size("bin/moon", 1);
+    assert_eq!((60, Some(400)), progress.totals());
+}
+
+#[test]
+fn test_download_redraw_throttle() {
+    let names = ["core.zip".to_string()];
+    let json = Output {
+        json: true,
+        ..Output::default()
+    };
+    let reported = |progress: &DownloadProgress| progress.files["core.zip"].reported;
+    let mut progress = DownloadProgress::new(&names, json);
+    progress.update("core.zip", 10, Some(100));
+    assert_eq!(Some(10), reported(&progress));
+
+    // updates right after a redraw are skipped
+    progress.last_draw = Some(Instant::now());
+    progress.update("core.zip", 20, Some(100));
+    assert_eq!(Some(10), reported(&progress));
+
+    // and drawn once the interval is over
+    progress.last_draw = Some(Instant::now() - REDRAW_INTERVAL);
+    progress.update("core.zip", 30, Some(100));
+    assert_eq!(Some(30), reported(&progress));
+
+    // the last update is never lost
+    progress.last_draw = Some(Instant::now());
+    progress.update("core.zip", 100, Some(100));
+    assert_eq!(Some(30), reported(&progress));
+    progress.finish();
+    assert_eq!(Some(100), reported(&progress));
+}
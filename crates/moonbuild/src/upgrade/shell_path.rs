@@ -0,0 +1,227 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Making sure the installed toolchain can be run from a shell
+
+use super::install;
+use super::output::Output;
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// Whether `bin` is one of the directories in `path`, a value of PATH
+pub fn in_path(path: &OsStr, bin: &Path) -> bool {
+    std::env::split_paths(path).any(|dir| install::same_path(&dir, bin))
+}
+
+/// Shell startup files the installer or users add moon to PATH in
+#[cfg(unix)]
+pub const SHELL_PROFILES: [&str; 6] = [
+    ".profile",
+    ".bash_profile",
+    ".bashrc",
+    ".zshenv",
+    ".zshrc",
+    ".config/fish/config.fish",
+];
+
+/// Lines of `content` that put `bin` on PATH, with their line numbers
+#[cfg(unix)]
+pub fn path_lines<'a>(content: &'a str, bin: &Path) -> Vec<(usize, &'a str)> {
+    let bin = bin.display().to_string();
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            !line.trim_start().starts_with('#')
+                && line.to_ascii_uppercase().contains("PATH")
+                && (line.contains(&bin) || line.contains(".moon/bin"))
+        })
+        .map(|(i, line)| (i + 1, line.trim()))
+        .collect()
+}
+
+/// The profile of `shell`, relative to the user's home, and the line that
+/// puts `bin` on PATH in it
+#[cfg(unix)]
+fn profile_line(shell: &str, bin: &Path) -> (&'static str, String) {
+    let export = format!("export PATH=\"{}:$PATH\"", bin.display());
+    match Path::new(shell).file_name().and_then(OsStr::to_str) {
+        Some("fish") => (
+            ".config/fish/config.fish",
+            format!("fish_add_path {}", bin.display()),
+        ),
+        Some("zsh") => (".zshrc", export),
+        Some("bash") => (".bashrc", export),
+        _ => (".profile", export),
+    }
+}
+
+#[cfg(unix)]
+fn user_shell() -> String {
+    std::env::var("SHELL").unwrap_or_default()
+}
+
+/// Put `bin` on PATH in the profile of the user's shell, returning where.
+/// Nothing is changed if the profile already does.
+#[cfg(unix)]
+fn add_to_path(bin: &Path) -> Result<String> {
+    let (profile, line) = profile_line(&user_shell(), bin);
+    let path = home::home_dir()
+        .context("failed to find the home directory")?
+        .join(profile);
+    let content = std::fs::read_to_string(&path).unwrap_or_default();
+    if path_lines(&content, bin).is_empty() {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        let separator = if content.is_empty() || content.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        let appended = format!(
+            "{}{}\n# added by moon upgrade\n{}\n",
+            content, separator, line
+        );
+        std::fs::write(&path, appended)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(path.display().to_string())
+}
+
+/// Put `bin` first in the Path of the user environment
+#[cfg(windows)]
+fn add_to_path(bin: &Path) -> Result<String> {
+    let bin = bin.display().to_string().replace('\'', "''");
+    let script = format!(
+        "$path = [Environment]::GetEnvironmentVariable('Path', 'User'); \
+         if (-not (($path -split ';') -contains '{bin}')) {{ \
+         [Environment]::SetEnvironmentVariable('Path', '{bin};' + $path, 'User') }}",
+        bin = bin
+    );
+    let status = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .status()
+        .context("failed to run powershell")?;
+    if !status.success() {
+        anyhow::bail!("powershell failed to update the user environment");
+    }
+    Ok("the user environment".to_string())
+}
+
+/// How to put `bin` on PATH by hand
+pub fn path_instructions(bin: &Path) -> String {
+    #[cfg(unix)]
+    {
+        let (profile, line) = profile_line(&user_shell(), bin);
+        format!("add this line to ~/{}:\n  {}", profile, line)
+    }
+    #[cfg(windows)]
+    {
+        format!(
+            "add {} to Path in the environment variables of your account",
+            bin.display()
+        )
+    }
+}
+
+/// After an install to `bin`, tell how to run the toolchain if it isn't on
+/// PATH, or put it there with `add`
+pub fn check_path(bin: &Path, add: bool, out: Output) {
+    if in_path(&std::env::var_os("PATH").unwrap_or_default(), bin) {
+        return;
+    }
+    if !add {
+        out.warn(&format!(
+            "Warning: {} is not on PATH, to run moon from a shell {}\nor run `moon upgrade --add-to-path` to add it",
+            bin.display(),
+            path_instructions(bin)
+        ));
+        return;
+    }
+    match add_to_path(bin) {
+        Ok(location) => {
+            if out.human() {
+                println!(
+                    "Added {} to PATH in {}, restart your shell to use it",
+                    bin.display(),
+                    location
+                );
+            }
+        }
+        Err(e) => out.warn(&format!(
+            "Warning: failed to add {} to PATH: {:#}\nTo add it manually, {}",
+            bin.display(),
+            e,
+            path_instructions(bin)
+        )),
+    }
+}
+
+#[test]
+fn test_in_path() {
+    let bin = tempfile::tempdir().unwrap();
+    let other = tempfile::tempdir().unwrap();
+    let path = std::env::join_paths([other.path(), bin.path()]).unwrap();
+    assert!(in_path(&path, bin.path()));
+    assert!(!in_path(&path, &bin.path().join("bin")));
+    assert!(!in_path(OsStr::new(""), bin.path()));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_profile_line() {
+    let bin = Path::new("/home/me/.moon/bin");
+    assert_eq!(
+        (
+            ".zshrc",
+            "export PATH=\"/home/me/.moon/bin:$PATH\"".to_string()
+        ),
+        profile_line("/bin/zsh", bin)
+    );
+    assert_eq!(
+        (
+            ".config/fish/config.fish",
+            "fish_add_path /home/me/.moon/bin".to_string()
+        ),
+        profile_line("/usr/local/bin/fish", bin)
+    );
+    assert_eq!(".profile", profile_line("", bin).0);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_path_lines() {
+    let content = "# export PATH=\"$HOME/.moon/bin:$PATH\"\n\
+                   alias ll='ls -l'\n\
+                   export PATH=\"$HOME/.moon/bin:$PATH\"\n\
+                   fish_add_path /opt/moon/bin\n";
+    assert_eq!(
+        vec![(3, "export PATH=\"$HOME/.moon/bin:$PATH\"")],
+        path_lines(content, Path::new("/home/me/.moon/bin"))
+    );
+    assert_eq!(
+        vec![
+            (3, "export PATH=\"$HOME/.moon/bin:$PATH\""),
+            (4, "fish_add_path /opt/moon/bin")
+        ],
+        path_lines(content, Path::new("/opt/moon/bin"))
+    );
+}
@@ -0,0 +1,129 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! A local HTTP server answering with canned responses, for tests of the
+//! requests sent to mirrors
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+/// A request received by [`serve`], without its body
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Value of the header `name`, case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// What [`serve`] answers a request with. `Content-Length` is the length of
+/// `body`, and the connection is closed after each response.
+pub struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    sent: Option<usize>,
+}
+
+impl Response {
+    /// `status` is the code and its reason, e.g. `200 OK`
+    pub fn new(status: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: body.into(),
+            sent: None,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Send only the first `len` bytes of the body, as if the connection
+    /// dropped
+    pub fn truncated(mut self, len: usize) -> Self {
+        self.sent = Some(len);
+        self
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Request {
+    let mut request_line = String::new();
+    let _ = reader.read_line(&mut request_line);
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Request {
+        method,
+        path,
+        headers,
+    }
+}
+
+/// Serve on a local port until the test ends, answering each connection on
+/// its own thread with `respond`. The body is left out for HEAD requests.
+pub fn serve(respond: impl Fn(&Request) -> Response + Send + Sync + 'static) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let respond = Arc::new(respond);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let respond = Arc::clone(&respond);
+            std::thread::spawn(move || {
+                let request = read_request(&mut BufReader::new(&stream));
+                let response = respond(&request);
+                let mut head = format!("HTTP/1.1 {}\r\n", response.status);
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str(&format!(
+                    "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    response.body.len()
+                ));
+                let mut stream = &stream;
+                let _ = stream.write_all(head.as_bytes());
+                if request.method != "HEAD" {
+                    let sent = response.sent.unwrap_or(response.body.len());
+                    let _ = stream.write_all(&response.body[..sent]);
+                }
+            });
+        }
+    });
+    addr
+}
@@ -0,0 +1,105 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Bandwidth limit shared by all concurrent downloads
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket refilled at `rate` bytes per second, holding at most one
+/// second worth of tokens. Taking more than available puts the bucket in
+/// debt, which the caller then sleeps off, so concurrent downloads together
+/// stay under the limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        RateLimiter {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// How long to wait after consuming `bytes`
+    fn take(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        *tokens -= bytes as f64;
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    pub async fn consume(&self, bytes: u64) {
+        let delay = self.take(bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Parse a rate like `500000`, `500K` or `2M`, suffixes are powers of 1024
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let multiplier: u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(format!("unknown unit `{}`, expected K, M or G", unit)),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid rate `{}`", s))?;
+    match value.checked_mul(multiplier) {
+        Some(0) | None => Err(format!("invalid rate `{}`", s)),
+        Some(rate) => Ok(rate),
+    }
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(Ok(500), parse_rate("500"));
+    assert_eq!(Ok(500 * 1024), parse_rate("500k"));
+    assert_eq!(Ok(2 * 1024 * 1024), parse_rate("2M"));
+    assert!(parse_rate("0").is_err());
+    assert!(parse_rate("2X").is_err());
+    assert!(parse_rate("fast").is_err());
+}
+
+#[test]
+fn test_rate_limiter_debt() {
+    let limiter = RateLimiter::new(1000);
+    assert_eq!(Duration::ZERO, limiter.take(1000));
+    let delay = limiter.take(500);
+    assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+}
@@ -0,0 +1,167 @@
+// moon: The build system and package manager for MoonBit.
+// Copyright (C) 2024 International Digital Economy Academy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+// For inquiries, you can contact us via e-mail at jichuruanjian@idea.edu.cn.
+
+//! Removal of the toolchain installed by the installer or `moon upgrade`
+
+use super::install::{self, BACKUPS_DIR};
+#[cfg(unix)]
+use super::shell_path::{path_lines, SHELL_PROFILES};
+use super::{
+    cache::VERSION_CACHE, installed_entries, manifest::TOOLCHAIN_MANIFEST, require_terminal,
+};
+use anyhow::{bail, Result};
+use colored::Colorize;
+use dialoguer::Confirm;
+use moonutil::moon_dir;
+use std::path::Path;
+
+#[derive(Debug, clap::Parser, Clone)]
+pub struct UninstallSubcommand {
+    /// Also remove the rest of the moon home, like the registry index,
+    /// credentials and caches
+    #[clap(long)]
+    pub purge: bool,
+
+    /// Uninstall without asking for confirmation
+    #[clap(long, short)]
+    pub yes: bool,
+}
+
+/// Entries of the moon home only used by the toolchain and `moon upgrade`
+const UPGRADE_ENTRIES: [&str; 5] = [
+    "channel",
+    VERSION_CACHE,
+    TOOLCHAIN_MANIFEST,
+    BACKUPS_DIR,
+    "tmp",
+];
+
+/// Removed when they are left empty
+const TOOLCHAIN_DIRS: [&str; 4] = ["bin/internal", "bin", "include", "lib"];
+
+/// Remove `path`, which may be the running moon
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+    if install::is_current_exe(path) {
+        return self_replace::self_delete();
+    }
+    #[cfg(windows)]
+    {
+        if std::env::current_exe().is_ok_and(|exe| exe.starts_with(path)) {
+            self_replace::self_delete_outside_path(path)?;
+        }
+    }
+    install::remove_path(path)
+}
+
+pub fn uninstall(cmd: UninstallSubcommand, quiet: bool, dry_run: bool) -> Result<i32> {
+    let home = moon_dir::home();
+    let targets = if cmd.purge {
+        vec![home.clone()]
+    } else {
+        installed_entries()
+            .into_iter()
+            .chain(UPGRADE_ENTRIES)
+            .flat_map(|entry| [home.join(entry), home.join(format!("{}.exe", entry))])
+            .filter(|path| path.symlink_metadata().is_ok())
+            .collect::<Vec<_>>()
+    };
+    if targets.is_empty() || !home.exists() {
+        if !quiet {
+            println!("No toolchain found in {}", home.display());
+        }
+        return Ok(0);
+    }
+    if dry_run {
+        println!("Would remove:");
+        for target in &targets {
+            println!("  {}", target.display());
+        }
+        return Ok(0);
+    }
+
+    let what = if cmd.purge {
+        "everything in"
+    } else {
+        "the toolchain in"
+    };
+    let confirm = cmd.yes || {
+        require_terminal("uninstall")?;
+        Confirm::new()
+            .with_prompt(format!(
+                "Will remove {} {}. Continue?",
+                what,
+                home.display().to_string().bold()
+            ))
+            .default(false)
+            .interact()?
+    };
+    if !confirm {
+        return Ok(0);
+    }
+
+    let mut failed = vec![];
+    for target in targets {
+        if let Err(e) = remove_entry(&target) {
+            failed.push(format!("  {}: {}", target.display(), e));
+        }
+    }
+    if !cmd.purge {
+        for dir in TOOLCHAIN_DIRS {
+            // only succeeds when empty
+            let _ = std::fs::remove_dir(home.join(dir));
+        }
+    }
+    if !failed.is_empty() {
+        bail!("failed to remove:\n{}", failed.join("\n"));
+    }
+    if !quiet {
+        println!("Removed {} {}", what, home.display());
+        if !cmd.purge {
+            println!("Your registry index, credentials and caches are kept, pass `--purge` to remove them too");
+        }
+    }
+
+    #[cfg(unix)]
+    report_path_entries(&home.join("bin"));
+    Ok(0)
+}
+
+/// The toolchain is gone but the shell still looks for it, point out where
+#[cfg(unix)]
+fn report_path_entries(bin: &Path) {
+    let user_home = match home::home_dir() {
+        Some(home) => home,
+        None => return,
+    };
+    let mut found = vec![];
+    for profile in SHELL_PROFILES {
+        let path = user_home.join(profile);
+        if let Ok(content) = std::fs::read_to_string(&path) {
+            for (line_number, line) in path_lines(&content, bin) {
+                found.push(format!("  {}:{}: {}", path.display(), line_number, line));
+            }
+        }
+    }
+    if !found.is_empty() {
+        println!(
+            "{}\n{}",
+            "These lines still add moon to PATH, remove them manually:".bold(),
+            found.join("\n")
+        );
+    }
+}